@@ -17,7 +17,14 @@
 #![no_std]
 #![no_main]
 
+mod serial_uart;
+use serial_uart::SerialUart;
+
+use embedded_hal::digital::v2::OutputPin;
+use hal::gpio::FunctionUart;
 use hal::pac;
+use hal::Clock;
+use heapless::spsc::Queue;
 use panic_halt as _;
 use rp_pico::hal;
 
@@ -25,6 +32,11 @@ use usb_device::bus::UsbBusAllocator;
 use usb_device::prelude::*;
 use usbd_serial::SerialPort;
 
+// 各方向のリングバッファの大きさ (Queueは1バイト少なく格納する)
+const USB_TO_UART_BUFFER_SIZE: usize = 1024;
+const UART_TO_USB_BUFFER_SIZE: usize = 1024;
+const USB_PACKET_SIZE: usize = 64;
+
 #[rp_pico::hal::entry]
 fn main() -> ! {
     let pac = pac::Peripherals::take().unwrap();
@@ -44,6 +56,26 @@ fn main() -> ! {
     )
     .ok()
     .unwrap();
+    // GPIOを初期化
+    let sio = hal::Sio::new(pac.SIO);
+    let pins = rp_pico::Pins::new(
+        pac.IO_BANK0,
+        pac.PADS_BANK0,
+        sio.gpio_bank0,
+        &mut resets,
+    );
+    // UART0をTX = GPIO0, RX = GPIO1 に割り当てる。ホストがライン・コーディングを設定するまでは無効
+    let uart = hal::uart::UartPeripheral::new(
+        pac.UART0,
+        (pins.gpio0.into_mode::<FunctionUart>(), pins.gpio1.into_mode::<FunctionUart>()),
+        &mut resets,
+    );
+    let mut uart = SerialUart::new(uart, clocks.peripheral_clock.freq().to_Hz());
+    // DTR = GPIO2, RTS = GPIO3 に出力する。RS-232Cの変換ICと同じく負論理 (アサートでLow)
+    let mut dtr_pin = pins.gpio2.into_push_pull_output();
+    let mut rts_pin = pins.gpio3.into_push_pull_output();
+    dtr_pin.set_high().ok();
+    rts_pin.set_high().ok();
     // UsbBusを初期化
     let usb_bus = hal::usb::UsbBus::new(
         pac.USBCTRL_REGS,   // RP2040のUSBペリフェラルのレジスタ
@@ -66,29 +98,69 @@ fn main() -> ! {
         .max_packet_size_0(64)              // 最大パケットサイズ (64バイト)
         .build();                           // 上記の設定でUsbDeviceを構築
 
-    // ループバック用のバッファ
-    let mut buffer = [0u8; 64];
-    // ループバック用バッファ内の送り返し待ちデータ
-    // ※本来は型指定不要で後続の文から型が推論される
-    let mut pending_bytes_to_write: Option<(usize, usize)> = None;
+    // ホストからUARTへの送信待ちデータ
+    let mut usb_to_uart: Queue<u8, USB_TO_UART_BUFFER_SIZE> = Queue::new();
+    // UARTから受信して、ホストへの送信待ちデータ
+    let mut uart_to_usb: Queue<u8, UART_TO_USB_BUFFER_SIZE> = Queue::new();
     loop {
-        // USBシリアルのホストからの受信データを読み出して送り返す
-        if pending_bytes_to_write.is_none() {   // 送り返し待ちデータなければ読む
-            if let Ok(bytes_read) = usb_serial.read(&mut buffer) {
-                pending_bytes_to_write = Some((0, bytes_read))  // 送り返し待ちデータ残量保存
+        // ホストが設定したライン・コーディングが変わったらUARTを設定し直す
+        uart.apply_line_coding(usb_serial.line_coding());
+        // DTR, RTSの状態をピンに出力する
+        if usb_serial.dtr() {
+            dtr_pin.set_low().ok();
+        } else {
+            dtr_pin.set_high().ok();
+        }
+        if usb_serial.rts() {
+            rts_pin.set_low().ok();
+        } else {
+            rts_pin.set_high().ok();
+        }
+        // USBシリアルのホストからの受信データをバッファに空きがある分だけ読む
+        // バッファが一杯の間は読まないので、OUTエンドポイントがNAKを返してホストが待つ
+        let space = usb_to_uart.capacity() - usb_to_uart.len();
+        if space > 0 {
+            let mut packet = [0u8; USB_PACKET_SIZE];
+            let length = space.min(packet.len());
+            if let Ok(bytes_read) = usb_serial.read(&mut packet[..length]) {
+                for &data in &packet[..bytes_read] {
+                    usb_to_uart.enqueue(data).ok();  // 空きは確認済みなので失敗しない
+                }
+            }
+        }
+        // UARTの送信FIFOに入るだけ書き込む
+        while let Some(&data) = usb_to_uart.peek() {
+            if uart.write(data).is_err() {
+                break;
             }
+            usb_to_uart.dequeue();
         }
-        if let Some((bytes_written, bytes_to_write)) = pending_bytes_to_write { // 送り返し待ちデータあり？
-            // バッファ内のデータを送信
-            if let Ok(bytes_written_now) = usb_serial.write(&buffer[bytes_written..bytes_to_write])
-            {
-                // 送り返し待ちデータの送信完了位置を更新
-                let bytes_written = bytes_written + bytes_written_now;
-                if bytes_written == bytes_to_write {    // 全部送り終わったらNoneにしておく
-                    pending_bytes_to_write = None;
-                } else {                                // 残ってるので更新
-                    pending_bytes_to_write = Some((bytes_written, bytes_to_write));
+        // UARTの受信FIFOからバッファに入るだけ読む。フレーミングエラーやパリティエラーのデータは捨てる
+        while !uart_to_usb.is_full() {
+            match uart.read() {
+                Ok(data) => {
+                    uart_to_usb.enqueue(data).ok();
+                }
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(())) => {}
+            }
+        }
+        // UARTから受信したデータを1パケット分ずつホストへ送る
+        while !uart_to_usb.is_empty() {
+            let mut packet = [0u8; USB_PACKET_SIZE];
+            let mut length = 0;
+            for (byte, &data) in packet.iter_mut().zip(uart_to_usb.iter()) {
+                *byte = data;
+                length += 1;
+            }
+            match usb_serial.write(&packet[..length]) {
+                Ok(bytes_written) if bytes_written > 0 => {
+                    // 送信できた分だけバッファから取り除く
+                    for _ in 0..bytes_written {
+                        uart_to_usb.dequeue();
+                    }
                 }
+                _ => break,
             }
         }
         // USBデバイスのイベントなどを処理する
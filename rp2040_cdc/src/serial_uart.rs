@@ -0,0 +1,131 @@
+// Copyright 2023 Kenta Ida
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use embedded_hal::serial::{Read, Write};
+use fugit::HertzU32;
+use rp_pico::hal::uart::{
+    DataBits, Disabled, Enabled, Parity, StopBits, UartConfig, UartDevice, UartPeripheral, ValidUartPinout,
+};
+use usbd_serial::{LineCoding, ParityType};
+
+enum State<D: UartDevice, P: ValidUartPinout<D>> {
+    Disabled(UartPeripheral<Disabled, D, P>),
+    Enabled(UartPeripheral<Enabled, D, P>),
+}
+
+/// UART which follows the line coding set by the host to the USB serial port.
+pub struct SerialUart<D: UartDevice, P: ValidUartPinout<D>> {
+    state: Option<State<D, P>>,
+    peripheral_clock_hz: u32,
+    // 最後に受け取ったライン・コーディング (ボーレート, データビット, パリティ, ストップビット)
+    line_coding: Option<(u32, u8, u8, u8)>,
+}
+
+impl<D: UartDevice, P: ValidUartPinout<D>> SerialUart<D, P> {
+    pub fn new(uart: UartPeripheral<Disabled, D, P>, peripheral_clock_hz: u32) -> Self {
+        Self {
+            state: Some(State::Disabled(uart)),
+            peripheral_clock_hz,
+            line_coding: None,
+        }
+    }
+
+    /// Reconfigures the UART if the line coding has changed.
+    ///
+    /// Line codings the UART does not support (mark/space parity, 1.5 stop bits, 16 data bits or an out of range baudrate) are ignored.
+    pub fn apply_line_coding(&mut self, line_coding: &LineCoding) {
+        let raw_line_coding = (
+            line_coding.data_rate(),
+            line_coding.data_bits(),
+            line_coding.parity_type() as u8,
+            line_coding.stop_bits() as u8,
+        );
+        if self.line_coding == Some(raw_line_coding) {
+            return;
+        }
+        self.line_coding = Some(raw_line_coding);
+        let config = match uart_config(line_coding) {
+            Some(config) => config,
+            None => return, // 対応していなければ前の設定のまま
+        };
+        // enableは範囲外の分周比を黙って丸めてしまうので、先に確認して前の設定のままにする
+        if self.actual_baudrate(line_coding.data_rate()) == 0 {
+            return;
+        }
+        let uart = match self.state.take() {
+            Some(State::Enabled(uart)) => uart.disable(),
+            Some(State::Disabled(uart)) => uart,
+            None => return,
+        };
+        // ボーレートが0の時だけ失敗するが、確認済みなので失敗しない
+        self.state = uart.enable(config, HertzU32::Hz(self.peripheral_clock_hz)).ok().map(State::Enabled);
+    }
+
+    /// Returns the baudrate the UART actually generates for `baudrate`, or 0 if out of range.
+    fn actual_baudrate(&self, baudrate: u32) -> u32 {
+        if baudrate == 0 {
+            return 0;
+        }
+        // PL011のボーレート分周比 (整数部16ビット、小数部6ビット) を求める
+        let divisor = (8 * self.peripheral_clock_hz as u64) / baudrate as u64;
+        let int = divisor >> 7;
+        let frac = (divisor & 0x7f).div_ceil(2);
+        if int == 0 || int >= 0xffff {
+            return 0;
+        }
+        ((4 * self.peripheral_clock_hz as u64) / (64 * int + frac)) as u32
+    }
+
+    /// Returns a received byte. Bytes broken by framing or parity errors are dropped.
+    pub fn read(&mut self) -> nb::Result<u8, ()> {
+        match self.state.as_mut() {
+            Some(State::Enabled(uart)) => uart.read().map_err(|err| err.map(|_| ())),
+            _ => Err(nb::Error::WouldBlock),
+        }
+    }
+
+    pub fn write(&mut self, data: u8) -> nb::Result<(), ()> {
+        match self.state.as_mut() {
+            Some(State::Enabled(uart)) => uart.write(data).map_err(|err| err.map(|_| ())),
+            _ => Err(nb::Error::WouldBlock),
+        }
+    }
+}
+
+fn uart_config(line_coding: &LineCoding) -> Option<UartConfig> {
+    if line_coding.data_rate() == 0 {
+        return None;
+    }
+    let data_bits = match line_coding.data_bits() {
+        5 => DataBits::Five,
+        6 => DataBits::Six,
+        7 => DataBits::Seven,
+        8 => DataBits::Eight,
+        _ => return None,
+    };
+    let parity = match line_coding.parity_type() {
+        ParityType::None => None,
+        ParityType::Odd => Some(Parity::Odd),
+        ParityType::Event => Some(Parity::Even),    // usbd-serial 0.1ではEvenがEventと綴られている
+        _ => return None,
+    };
+    let stop_bits = match line_coding.stop_bits() {
+        usbd_serial::StopBits::One => StopBits::One,
+        usbd_serial::StopBits::Two => StopBits::Two,
+        _ => return None,
+    };
+    Some(UartConfig::new(HertzU32::Hz(line_coding.data_rate()), data_bits, parity, stop_bits))
+}
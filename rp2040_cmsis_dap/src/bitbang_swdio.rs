@@ -0,0 +1,665 @@
+// Copyright 2023 Kenta Ida
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+
+use crate::dap_processor::DapError;
+use crate::jtagio::JtagIo;
+use crate::swdio::*;
+
+/// GPIO pin whose direction can be switched at runtime.
+pub trait InputOutputPin: InputPin + OutputPin {
+    fn set_as_input(&mut self);
+    fn set_as_output(&mut self);
+}
+
+/// Busy wait used to make the SWCLK period.
+pub trait CycleDelay {
+    fn delay_cycles(&mut self, cycles: u32);
+}
+
+impl<F: FnMut(u32)> CycleDelay for F {
+    fn delay_cycles(&mut self, cycles: u32) {
+        self(cycles)
+    }
+}
+
+/// Type of the nRESET pin of the probe which does not have it.
+pub struct NoPin;
+
+impl InputPin for NoPin {
+    type Error = core::convert::Infallible;
+
+    fn is_high(&self) -> Result<bool, Self::Error> {
+        Ok(false)
+    }
+
+    fn is_low(&self) -> Result<bool, Self::Error> {
+        Ok(true)
+    }
+}
+
+impl OutputPin for NoPin {
+    type Error = core::convert::Infallible;
+
+    fn set_low(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn set_high(&mut self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+}
+
+impl InputOutputPin for NoPin {
+    fn set_as_input(&mut self) {}
+    fn set_as_output(&mut self) {}
+}
+
+/// `SwdIo` implementation which toggles SWCLK/SWDIO by software.
+///
+/// The SWCLK half period is `SwdIoConfig::clock_wait_cycles` cycles of `delay`,
+/// which counts `delay_cycles_per_second` cycles per second.
+///
+/// nRESET is controlled only if the pin is given by `with_nreset`.
+pub struct BitbangSwdIo<C, D, W, R = NoPin> {
+    swclk: C,
+    swdio: D,
+    delay: W,
+    delay_cycles_per_second: u32,
+    nreset: Option<R>,
+}
+
+impl<C, D, W> BitbangSwdIo<C, D, W>
+where
+    C: InputOutputPin,
+    D: InputOutputPin,
+    W: CycleDelay,
+{
+    pub fn new(swclk: C, swdio: D, delay: W, delay_cycles_per_second: u32) -> Self {
+        let mut io = Self {
+            swclk,
+            swdio,
+            delay,
+            delay_cycles_per_second,
+            nreset: None,
+        };
+        // 接続されるまではハイ・インピーダンスにしておく
+        io.swclk.set_as_input();
+        io.swdio.set_as_input();
+        io
+    }
+
+    /// Controls nRESET by `nreset`.
+    ///
+    /// nRESET is asserted by driving the pin low and deasserted by releasing it,
+    /// so the pin must be pulled up externally.
+    pub fn with_nreset<R: InputOutputPin>(self, mut nreset: R) -> BitbangSwdIo<C, D, W, R> {
+        nreset.set_as_input();
+        BitbangSwdIo {
+            swclk: self.swclk,
+            swdio: self.swdio,
+            delay: self.delay,
+            delay_cycles_per_second: self.delay_cycles_per_second,
+            nreset: Some(nreset),
+        }
+    }
+}
+
+impl<C, D, W, R> BitbangSwdIo<C, D, W, R>
+where
+    C: InputOutputPin,
+    D: InputOutputPin,
+    W: CycleDelay,
+    R: InputOutputPin,
+{
+    pub fn release(self) -> (C, D, W, Option<R>) {
+        (self.swclk, self.swdio, self.delay, self.nreset)
+    }
+
+    fn set_pin<P: OutputPin>(pin: &mut P, high: bool) {
+        if high {
+            pin.set_high().ok();
+        } else {
+            pin.set_low().ok();
+        }
+    }
+
+    fn clock_cycle(&mut self, config: &SwdIoConfig) {
+        self.swclk.set_low().ok();
+        self.delay.delay_cycles(config.clock_wait_cycles);
+        self.swclk.set_high().ok();
+        self.delay.delay_cycles(config.clock_wait_cycles);
+    }
+}
+
+impl<C, D, W, R> SwdBitIo for BitbangSwdIo<C, D, W, R>
+where
+    C: InputOutputPin,
+    D: InputOutputPin,
+    W: CycleDelay,
+    R: InputOutputPin,
+{
+    fn write_bits(&mut self, config: &SwdIoConfig, count: usize, data: u32) {
+        self.swdio.set_as_output();
+        for index in 0..count {
+            // 立ち下がりエッジの前に出力し、ターゲットは立ち上がりエッジで読み込む
+            Self::set_pin(&mut self.swdio, data & (1 << index) != 0);
+            self.clock_cycle(config);
+        }
+    }
+
+    fn read_bits(&mut self, config: &SwdIoConfig, count: usize) -> u32 {
+        self.swdio.set_as_input();
+        let mut data = 0;
+        for index in 0..count {
+            // ターゲットが立ち上がりエッジで出力した値を、次の立ち上がりエッジの前に読み込む
+            self.swclk.set_low().ok();
+            self.delay.delay_cycles(config.clock_wait_cycles);
+            if self.swdio.is_high().unwrap_or(false) {
+                data |= 1 << index;
+            }
+            self.swclk.set_high().ok();
+            self.delay.delay_cycles(config.clock_wait_cycles);
+        }
+        data
+    }
+}
+
+impl<C, D, W, R> SwdIo for BitbangSwdIo<C, D, W, R>
+where
+    C: InputOutputPin,
+    D: InputOutputPin,
+    W: CycleDelay,
+    R: InputOutputPin,
+{
+    fn connect(&mut self) {
+        self.swclk.set_high().ok();
+        self.swclk.set_as_output();
+        self.swdio.set_high().ok();
+        self.swdio.set_as_output();
+    }
+
+    fn disconnect(&mut self) {
+        self.swclk.set_as_input();
+        self.swdio.set_as_input();
+    }
+
+    fn swj_clock(
+        &mut self,
+        config: &mut SwdIoConfig,
+        frequency_hz: u32,
+    ) -> core::result::Result<(), DapError> {
+        if frequency_hz == 0 {
+            return Err(DapError::InvalidCommand);
+        }
+        // 1周期のうち半分ずつLowとHighを出力する
+        config.clock_wait_cycles = self.delay_cycles_per_second / 2 / frequency_hz;
+        Ok(())
+    }
+
+    fn swj_sequence(&mut self, config: &SwdIoConfig, count: usize, data: &[u8]) {
+        write_sequence_bits(self, config, count, data);
+    }
+
+    fn swd_read_sequence(&mut self, config: &SwdIoConfig, count: usize, data: &mut [u8]) {
+        read_sequence_bits(self, config, count, data);
+    }
+
+    fn swd_write_sequence(&mut self, config: &SwdIoConfig, count: usize, data: &[u8]) {
+        write_sequence_bits(self, config, count, data);
+    }
+
+    fn swd_transfer(
+        &mut self,
+        config: &SwdIoConfig,
+        request: SwdRequest,
+        data: u32,
+    ) -> core::result::Result<u32, DapError> {
+        swd_transfer_bits(self, config, request, data)
+    }
+
+    fn enable_output(&mut self) {
+        self.swdio.set_as_output();
+    }
+
+    fn disable_output(&mut self) {
+        self.swdio.set_as_input();
+    }
+
+    fn set_swj_pins(&mut self, output: u8, select: u8) {
+        if select & SWJ_PIN_SWCLK_TCK != 0 {
+            Self::set_pin(&mut self.swclk, output & SWJ_PIN_SWCLK_TCK != 0);
+        }
+        if select & SWJ_PIN_SWDIO_TMS != 0 {
+            Self::set_pin(&mut self.swdio, output & SWJ_PIN_SWDIO_TMS != 0);
+        }
+        if let Some(nreset) = self.nreset.as_mut() {
+            if select & SWJ_PIN_NRESET != 0 {
+                if output & SWJ_PIN_NRESET != 0 {
+                    // オープンドレインなので、解除するときは出力をやめる
+                    nreset.set_as_input();
+                } else {
+                    nreset.set_low().ok();
+                    nreset.set_as_output();
+                }
+            }
+        }
+    }
+
+    fn get_swj_pins(&mut self) -> u8 {
+        let mut pins = 0;
+        if self.swclk.is_high().unwrap_or(false) {
+            pins |= SWJ_PIN_SWCLK_TCK;
+        }
+        if self.swdio.is_high().unwrap_or(false) {
+            pins |= SWJ_PIN_SWDIO_TMS;
+        }
+        // nRESETピンが無ければ状態は分からないので、ビットは立てない
+        if let Some(nreset) = self.nreset.as_ref() {
+            if nreset.is_high().unwrap_or(false) {
+                pins |= SWJ_PIN_NRESET;
+            }
+        }
+        pins
+    }
+
+    fn delay_us(&mut self, us: u32) {
+        let cycles = self.delay_cycles_per_second as u64 * us as u64 / 1_000_000;
+        self.delay.delay_cycles(cycles as u32);
+    }
+}
+
+/// `SwdIo` and `JtagIo` implementation which toggles the pins by software.
+///
+/// TCK and TMS share the pins with SWCLK and SWDIO.
+pub struct BitbangSwjIo<C, D, I, O, W, R = NoPin> {
+    swd: BitbangSwdIo<C, D, W, R>,
+    tdi: I,
+    tdo: O,
+}
+
+impl<C, D, I, O, W> BitbangSwjIo<C, D, I, O, W>
+where
+    C: InputOutputPin,
+    D: InputOutputPin,
+    I: OutputPin,
+    O: InputPin,
+    W: CycleDelay,
+{
+    pub fn new(swclk: C, swdio: D, tdi: I, tdo: O, delay: W, delay_cycles_per_second: u32) -> Self {
+        Self {
+            swd: BitbangSwdIo::new(swclk, swdio, delay, delay_cycles_per_second),
+            tdi,
+            tdo,
+        }
+    }
+
+    /// Controls nRESET by `nreset`. See `BitbangSwdIo::with_nreset`.
+    pub fn with_nreset<R: InputOutputPin>(self, nreset: R) -> BitbangSwjIo<C, D, I, O, W, R> {
+        BitbangSwjIo {
+            swd: self.swd.with_nreset(nreset),
+            tdi: self.tdi,
+            tdo: self.tdo,
+        }
+    }
+}
+
+impl<C, D, I, O, W, R> BitbangSwjIo<C, D, I, O, W, R>
+where
+    C: InputOutputPin,
+    D: InputOutputPin,
+    I: OutputPin,
+    O: InputPin,
+    W: CycleDelay,
+    R: InputOutputPin,
+{
+    pub fn release(self) -> (C, D, I, O, W, Option<R>) {
+        let (swclk, swdio, delay, nreset) = self.swd.release();
+        (swclk, swdio, self.tdi, self.tdo, delay, nreset)
+    }
+}
+
+impl<C, D, I, O, W, R> SwdIo for BitbangSwjIo<C, D, I, O, W, R>
+where
+    C: InputOutputPin,
+    D: InputOutputPin,
+    I: OutputPin,
+    O: InputPin,
+    W: CycleDelay,
+    R: InputOutputPin,
+{
+    fn connect(&mut self) {
+        self.swd.connect();
+    }
+
+    fn disconnect(&mut self) {
+        self.swd.disconnect();
+    }
+
+    fn swj_clock(
+        &mut self,
+        config: &mut SwdIoConfig,
+        frequency_hz: u32,
+    ) -> core::result::Result<(), DapError> {
+        self.swd.swj_clock(config, frequency_hz)
+    }
+
+    fn swj_sequence(&mut self, config: &SwdIoConfig, count: usize, data: &[u8]) {
+        self.swd.swj_sequence(config, count, data);
+    }
+
+    fn swd_read_sequence(&mut self, config: &SwdIoConfig, count: usize, data: &mut [u8]) {
+        self.swd.swd_read_sequence(config, count, data);
+    }
+
+    fn swd_write_sequence(&mut self, config: &SwdIoConfig, count: usize, data: &[u8]) {
+        self.swd.swd_write_sequence(config, count, data);
+    }
+
+    fn swd_transfer(
+        &mut self,
+        config: &SwdIoConfig,
+        request: SwdRequest,
+        data: u32,
+    ) -> core::result::Result<u32, DapError> {
+        self.swd.swd_transfer(config, request, data)
+    }
+
+    fn enable_output(&mut self) {
+        self.swd.enable_output();
+    }
+
+    fn disable_output(&mut self) {
+        self.swd.disable_output();
+    }
+
+    fn set_swj_pins(&mut self, output: u8, select: u8) {
+        self.swd.set_swj_pins(output, select);
+        if select & SWJ_PIN_TDI != 0 {
+            BitbangSwdIo::<C, D, W, R>::set_pin(&mut self.tdi, output & SWJ_PIN_TDI != 0);
+        }
+    }
+
+    fn get_swj_pins(&mut self) -> u8 {
+        let mut pins = self.swd.get_swj_pins();
+        if self.tdo.is_high().unwrap_or(false) {
+            pins |= SWJ_PIN_TDO;
+        }
+        pins
+    }
+
+    fn delay_us(&mut self, us: u32) {
+        self.swd.delay_us(us);
+    }
+
+    fn jtag(&mut self) -> Option<&mut dyn JtagIo> {
+        Some(self)
+    }
+}
+
+impl<C, D, I, O, W, R> JtagIo for BitbangSwjIo<C, D, I, O, W, R>
+where
+    C: InputOutputPin,
+    D: InputOutputPin,
+    I: OutputPin,
+    O: InputPin,
+    W: CycleDelay,
+    R: InputOutputPin,
+{
+    fn jtag_connect(&mut self) {
+        // TCK, TMS, TDIを出力にする
+        self.tdi.set_high().ok();
+        self.swd.connect();
+    }
+
+    fn jtag_disconnect(&mut self) {
+        self.swd.disconnect();
+    }
+
+    fn jtag_sequence(&mut self, config: &SwdIoConfig, count: usize, tms: bool, tdi: u64) -> u64 {
+        BitbangSwdIo::<C, D, W, R>::set_pin(&mut self.swd.swdio, tms);
+        let mut tdo = 0;
+        for index in 0..count {
+            BitbangSwdIo::<C, D, W, R>::set_pin(&mut self.tdi, tdi & (1 << index) != 0);
+            // ターゲットは立ち下がりエッジでTDOを出力し、立ち上がりエッジでTMS, TDIを読み込む
+            self.swd.swclk.set_low().ok();
+            self.swd.delay.delay_cycles(config.clock_wait_cycles);
+            if self.tdo.is_high().unwrap_or(false) {
+                tdo |= 1 << index;
+            }
+            self.swd.swclk.set_high().ok();
+            self.swd.delay.delay_cycles(config.clock_wait_cycles);
+        }
+        tdo
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::cell::RefCell;
+    use core::convert::Infallible;
+    use std::collections::VecDeque;
+    use std::rc::Rc;
+
+    /// Signals between the probe and the target.
+    ///
+    /// The SWDIO levels driven by the probe are sampled at the rising edges of SWCLK,
+    /// and `target_bits` are driven one by one while the probe releases SWDIO.
+    #[derive(Default)]
+    struct Wire {
+        swclk: bool,
+        swdio: bool,
+        swdio_output: bool,
+        nreset: bool,
+        nreset_output: bool,
+        sampled: Vec<bool>,
+        target_bits: VecDeque<bool>,
+    }
+
+    impl Wire {
+        fn push_target_bits(&mut self, count: usize, data: u64) {
+            self.target_bits.extend((0..count).map(|index| data & (1 << index) != 0));
+        }
+    }
+
+    #[derive(Clone, Copy)]
+    enum Signal {
+        Swclk,
+        Swdio,
+        Nreset,
+    }
+
+    struct MockPin {
+        wire: Rc<RefCell<Wire>>,
+        signal: Signal,
+    }
+
+    impl MockPin {
+        fn drive(&mut self, high: bool) {
+            let mut wire = self.wire.borrow_mut();
+            match self.signal {
+                Signal::Swclk => {
+                    if high && !wire.swclk {
+                        // 立ち上がりエッジでターゲットがSWDIOを読み込むか、次のビットを出力する
+                        if wire.swdio_output {
+                            let level = wire.swdio;
+                            wire.sampled.push(level);
+                        } else {
+                            wire.target_bits.pop_front();
+                        }
+                    }
+                    wire.swclk = high;
+                }
+                Signal::Swdio => wire.swdio = high,
+                Signal::Nreset => wire.nreset = high,
+            }
+        }
+    }
+
+    impl InputPin for MockPin {
+        type Error = Infallible;
+
+        fn is_high(&self) -> Result<bool, Infallible> {
+            let wire = self.wire.borrow();
+            // 出力していないピンはプルアップされている
+            Ok(match self.signal {
+                Signal::Swclk => wire.swclk,
+                Signal::Swdio if wire.swdio_output => wire.swdio,
+                Signal::Swdio => wire.target_bits.front().copied().unwrap_or(true),
+                Signal::Nreset => !wire.nreset_output || wire.nreset,
+            })
+        }
+
+        fn is_low(&self) -> Result<bool, Infallible> {
+            self.is_high().map(|high| !high)
+        }
+    }
+
+    impl OutputPin for MockPin {
+        type Error = Infallible;
+
+        fn set_low(&mut self) -> Result<(), Infallible> {
+            self.drive(false);
+            Ok(())
+        }
+
+        fn set_high(&mut self) -> Result<(), Infallible> {
+            self.drive(true);
+            Ok(())
+        }
+    }
+
+    impl InputOutputPin for MockPin {
+        fn set_as_input(&mut self) {
+            self.set_direction(false);
+        }
+
+        fn set_as_output(&mut self) {
+            self.set_direction(true);
+        }
+    }
+
+    impl MockPin {
+        fn set_direction(&mut self, output: bool) {
+            let mut wire = self.wire.borrow_mut();
+            match self.signal {
+                Signal::Swclk => {}
+                Signal::Swdio => wire.swdio_output = output,
+                Signal::Nreset => wire.nreset_output = output,
+            }
+        }
+    }
+
+    fn pin(wire: &Rc<RefCell<Wire>>, signal: Signal) -> MockPin {
+        MockPin {
+            wire: wire.clone(),
+            signal,
+        }
+    }
+
+    fn bitbang(wire: &Rc<RefCell<Wire>>) -> BitbangSwdIo<MockPin, MockPin, impl CycleDelay> {
+        let mut io = BitbangSwdIo::new(pin(wire, Signal::Swclk), pin(wire, Signal::Swdio), |_| {}, 1_000_000);
+        io.connect();
+        io
+    }
+
+    fn bits(count: usize, data: u64) -> Vec<bool> {
+        (0..count).map(|index| data & (1 << index) != 0).collect()
+    }
+
+    #[test]
+    fn swj_sequence_is_clocked_out_lsb_first() {
+        let wire = Rc::new(RefCell::new(Wire::default()));
+        let mut io = bitbang(&wire);
+        io.swj_sequence(&SwdIoConfig::default(), 12, &[0xa5, 0x0c]);
+        assert_eq!(wire.borrow().sampled, bits(12, 0xca5));
+    }
+
+    #[test]
+    fn read_transfer() {
+        let wire = Rc::new(RefCell::new(Wire::default()));
+        let mut io = bitbang(&wire);
+        let config = SwdIoConfig::default();
+        // ターンアラウンド, ACK (OK), データ, パリティ
+        wire.borrow_mut().push_target_bits(1 + 3, 0b0010);
+        wire.borrow_mut().push_target_bits(32, 0x2ba0_1477);
+        wire.borrow_mut().push_target_bits(1, 0);
+        assert_eq!(io.swd_transfer(&config, DAP_TRANSFER_RNW | DP_IDCODE, 0), Ok(0x2ba0_1477));
+        // Start, APnDP=0, RnW=1, A2=0, A3=0, Parity=1, Stop, Park
+        assert_eq!(wire.borrow().sampled, bits(8, 0xa5));
+    }
+
+    #[test]
+    fn read_transfer_with_parity_error() {
+        let wire = Rc::new(RefCell::new(Wire::default()));
+        let mut io = bitbang(&wire);
+        let config = SwdIoConfig::default();
+        wire.borrow_mut().push_target_bits(1 + 3, 0b0010);
+        wire.borrow_mut().push_target_bits(32, 0x2ba0_1477);
+        wire.borrow_mut().push_target_bits(1, 1);
+        assert_eq!(
+            io.swd_transfer(&config, DAP_TRANSFER_RNW | DP_IDCODE, 0),
+            Err(DapError::SwdError(DAP_TRANSFER_ERROR))
+        );
+    }
+
+    #[test]
+    fn write_transfer() {
+        let wire = Rc::new(RefCell::new(Wire::default()));
+        let mut io = bitbang(&wire);
+        let config = SwdIoConfig::default();
+        wire.borrow_mut().push_target_bits(1 + 3, 0b0010);
+        assert_eq!(io.swd_transfer(&config, DP_SELECT, 0x0100_00f0), Ok(0x0100_00f0));
+        let mut expected = bits(8, 0xb1);
+        expected.extend(bits(32, 0x0100_00f0));
+        expected.extend(bits(1, 1));
+        assert_eq!(wire.borrow().sampled, expected);
+    }
+
+    #[test]
+    fn wait_response() {
+        let wire = Rc::new(RefCell::new(Wire::default()));
+        let mut io = bitbang(&wire);
+        wire.borrow_mut().push_target_bits(1 + 3, 0b0100);
+        assert_eq!(
+            io.swd_transfer(&SwdIoConfig::default(), DAP_TRANSFER_RNW | DP_IDCODE, 0),
+            Err(DapError::SwdError(DAP_TRANSFER_WAIT))
+        );
+    }
+
+    #[test]
+    fn nreset_is_not_reported_without_pin() {
+        let wire = Rc::new(RefCell::new(Wire::default()));
+        let mut io = bitbang(&wire);
+        io.set_swj_pins(SWJ_PIN_NRESET, SWJ_PIN_NRESET);
+        assert_eq!(io.get_swj_pins() & SWJ_PIN_NRESET, 0);
+    }
+
+    #[test]
+    fn nreset_is_driven_low_and_released() {
+        let wire = Rc::new(RefCell::new(Wire::default()));
+        let mut io = bitbang(&wire).with_nreset(pin(&wire, Signal::Nreset));
+        assert_eq!(io.get_swj_pins() & SWJ_PIN_NRESET, SWJ_PIN_NRESET);
+        io.set_swj_pins(0, SWJ_PIN_NRESET);
+        assert!(wire.borrow().nreset_output);
+        assert_eq!(io.get_swj_pins() & SWJ_PIN_NRESET, 0);
+        io.set_swj_pins(SWJ_PIN_NRESET, SWJ_PIN_NRESET);
+        assert!(!wire.borrow().nreset_output);
+        assert_eq!(io.get_swj_pins() & SWJ_PIN_NRESET, SWJ_PIN_NRESET);
+    }
+}
@@ -14,11 +14,25 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use usb_device::{Result, control::RequestType};
+use heapless::Deque;
+use usb_device::{Result, control::{Recipient, Request, RequestType}};
 use usb_device::bus::UsbBusAllocator;
 use usb_device::class_prelude::*;
 use usb_device::device::DEFAULT_ALTERNATE_SETTING;
 use num_enum::{IntoPrimitive, TryFromPrimitive};
+use usbd_serial::{LineCoding, ParityType, SerialPort, StopBits};
+
+use crate::dap_info::DapInfo;
+use crate::dap_uart::{
+    DapUart, NoUart, UartIo, UartLineConfig, UartParity, UartStopBits, UART_TRANSPORT_USB_COM_PORT,
+};
+use crate::dap_processor::DapProcessor;
+use crate::target_reset::ResetTargetFn;
+use crate::swdio::SwdIo;
+use crate::swo::{SwoReceiver, NoSwo, SWO_TRANSPORT_STREAM};
+
+/// Size of a CMSIS-DAP packet.
+pub const PACKET_SIZE: usize = 64;
 
 const USB_IF_CLASS_VENDOR: u8 = 0xff;
 const USB_IF_SUBCLASS_VENDOR: u8 = 0x00;
@@ -46,88 +60,419 @@ enum RegPropertyType {
 
 const MS_VENDOR_CODE: u8 = 0x01;
 
-pub struct CmsisDapInterface<'a, B: UsbBus> {
-    interface: InterfaceNumber,
-    serial_string: StringIndex,
+const USB_IF_CLASS_HID: u8 = 0x03;
+const USB_IF_SUBCLASS_NONE: u8 = 0x00;
+
+const HID_DESCRIPTOR_TYPE_HID: u8 = 0x21;
+const HID_DESCRIPTOR_TYPE_REPORT: u8 = 0x22;
+const HID_REQUEST_GET_REPORT: u8 = 0x01;
+const HID_REQUEST_SET_IDLE: u8 = 0x0a;
+const HID_REPORT_SIZE: u16 = PACKET_SIZE as u16;
+const HID_POLL_INTERVAL_MS: u8 = 1;
+
+// 64バイトの入力・出力レポートを持つベンダ定義のHIDデバイス
+#[rustfmt::skip]
+const HID_REPORT_DESCRIPTOR: &[u8] = &[
+    0x06, 0x00, 0xff,   // Usage Page (Vendor Defined 0xFF00)
+    0x09, 0x01,         // Usage (0x01)
+    0xa1, 0x01,         // Collection (Application)
+    0x15, 0x00,         //   Logical Minimum (0)
+    0x26, 0xff, 0x00,   //   Logical Maximum (255)
+    0x75, 0x08,         //   Report Size (8)
+    0x95, 0x40,         //   Report Count (64)
+    0x09, 0x01,         //   Usage (0x01)
+    0x81, 0x02,         //   Input (Data, Variable, Absolute)
+    0x95, 0x40,         //   Report Count (64)
+    0x09, 0x01,         //   Usage (0x01)
+    0x91, 0x02,         //   Output (Data, Variable, Absolute)
+    0x95, 0x01,         //   Report Count (1)
+    0x09, 0x01,         //   Usage (0x01)
+    0xb1, 0x02,         //   Feature (Data, Variable, Absolute)
+    0xc0,               // End Collection
+];
+
+const USB_COM_PORT_PACKET_SIZE: usize = 64;
+
+const DAP_TRANSFER_ABORT: u8 = 0x07;
+const DAP_QUEUE_COMMANDS: u8 = 0x7e;
+
+/// CMSIS-DAP packet with its length.
+type Packet = ([u8; PACKET_SIZE], usize);
+
+/// Endpoints which carry CMSIS-DAP packets, with the packets waiting to be processed or sent.
+struct DapEndpoints<'a, B: UsbBus, const N: usize> {
     out_ep: EndpointOut<'a, B>,
     in_ep: EndpointIn<'a, B>,
-    response_buffer: [u8; 64],
-    pending_response_bytes: Option<usize>,
+    queued_requests: Deque<Packet, N>,
+    responses: Deque<Packet, N>,
+    next_request: Option<Packet>,
+    hid: bool,
 }
 
-impl<B: UsbBus> CmsisDapInterface<'_, B> {
-    pub fn new(alloc: &UsbBusAllocator<B>, max_packet_size: u16) -> CmsisDapInterface<'_, B> {
+impl<'a, B: UsbBus, const N: usize> DapEndpoints<'a, B, N> {
+    fn new(out_ep: EndpointOut<'a, B>, in_ep: EndpointIn<'a, B>, hid: bool) -> Self {
+        Self {
+            out_ep,
+            in_ep,
+            queued_requests: Deque::new(),      // DAP_QueueCommandsで保持しているリクエスト
+            responses: Deque::new(),            // 返信まちレスポンス
+            next_request: None,                 // 転送中に受信した次のリクエスト
+            hid,                                // HIDのレポートは64バイト固定長
+        }
+    }
+
+    fn poll<S: SwdIo, W: SwoReceiver, U: UartIo>(&mut self, processor: &mut DapProcessor<S, W, U>) -> Result<()> {
+        // 未送信レスポンスがあれば順に送信する。送信できなくてもリクエストの受信は続ける
+        self.send_responses().ok();
+        // レスポンスを格納できる間はホストからパケットを受信する
+        // 保持しているDAP_QueueCommandsのパケットも後でレスポンスを返すので数に入れる
+        while self.responses.len() + self.queued_requests.len() < N {
+            // 転送中に受信済みのリクエストがあればそちらを先に処理する
+            let (request_buffer, request_length) = match self.next_request.take() {
+                Some(next_request) => next_request,
+                None => {
+                    let mut request_buffer = [0u8; PACKET_SIZE];
+                    match self.out_ep.read(&mut request_buffer) {
+                        Ok(request_length) => (request_buffer, request_length),
+                        Err(UsbError::WouldBlock) => break,
+                        Err(err) => return Err(err),
+                    }
+                }
+            };
+            self.receive_request(processor, &request_buffer[..request_length]);
+        }
+        self.send_responses()
+    }
+
+    fn receive_request<S: SwdIo, W: SwoReceiver, U: UartIo>(&mut self, processor: &mut DapProcessor<S, W, U>, request: &[u8]) {
+        match request.first() {
+            None => return,
+            Some(&DAP_TRANSFER_ABORT) => {
+                // 中止する転送がないので何もしない。レスポンスも返さない
+                return;
+            }
+            Some(&DAP_QUEUE_COMMANDS) if self.responses.len() + self.queued_requests.len() + 1 < N => {
+                // DAP_QueueCommands以外のパケットを受信するまで実行しない
+                let mut packet = [0u8; PACKET_SIZE];
+                packet[..request.len()].copy_from_slice(request);
+                self.queued_requests.push_back((packet, request.len())).ok();
+                return;
+            }
+            _ => {}
+        }
+        // 保持していたパケットから順に処理する
+        while let Some((queued_request, queued_length)) = self.queued_requests.pop_front() {
+            self.process_request(processor, &queued_request[..queued_length]);
+        }
+        self.process_request(processor, request);
+    }
+
+    fn process_request<S: SwdIo, W: SwoReceiver, U: UartIo>(&mut self, processor: &mut DapProcessor<S, W, U>, request: &[u8]) {
+        // 転送中に受信したDAP_TransferAbortで転送を中止する。それ以外のパケットは次のリクエストとして保持する
+        // 一度中止したら、このリクエストの残りの転送も中止する。後続のパケットは読まずに残しておく
+        let out_ep = &self.out_ep;
+        let next_request = &mut self.next_request;
+        let mut aborted = false;
+        let mut abort = || {
+            if aborted {
+                return true;
+            }
+            if next_request.is_some() {
+                return false;
+            }
+            let mut buffer = [0u8; PACKET_SIZE];
+            match out_ep.read(&mut buffer) {
+                Ok(length) if length > 0 && buffer[0] == DAP_TRANSFER_ABORT => {
+                    aborted = true;
+                    true
+                }
+                Ok(length) => {
+                    *next_request = Some((buffer, length));
+                    false
+                }
+                Err(_) => false,
+            }
+        };
+        let mut response = [0u8; PACKET_SIZE];
+        let length = if self.hid {
+            // リクエストの残りは0で埋められているので、先頭のコマンドだけを処理する
+            processor.process_command_with_abort(request, &mut response, &mut abort);
+            // レスポンスも残りを0で埋めたレポートサイズのまま返す
+            PACKET_SIZE
+        } else {
+            processor.process_with_abort(request, &mut response, &mut abort)
+        };
+        // pollで空きを確認してから受信しているので、一杯にはならない
+        self.responses.push_back((response, length)).ok();
+    }
+
+    fn send_responses(&mut self) -> Result<()> {
+        while let Some((response, length)) = self.responses.front() {
+            self.in_ep.write(&response[..*length])?;
+            // 送信成功したので取り除く
+            self.responses.pop_front();
+        }
+        Ok(())
+    }
+}
+
+/// HID interface which carries CMSIS-DAP packets as 64 byte reports. (CMSIS-DAP v1)
+struct HidInterface<'a, B: UsbBus, const N: usize> {
+    interface: InterfaceNumber,
+    interface_string: StringIndex,
+    endpoints: DapEndpoints<'a, B, N>,
+}
+
+/// CDC-ACM port bridged to the target UART. (UART transport 1)
+struct UsbComPort<'a, B: UsbBus> {
+    serial: SerialPort<'a, B>,
+    // 最後にUARTに設定したライン・コーディング
+    line_config: Option<UartLineConfig>,
+    // UARTから受信して、まだホストに送っていないデータ
+    rx_packet: Option<([u8; USB_COM_PORT_PACKET_SIZE], usize, usize)>,
+}
+
+impl<B: UsbBus> UsbComPort<'_, B> {
+    fn poll<U: UartIo>(&mut self, uart: &mut DapUart<U>) -> Result<()> {
+        if uart.transport() != UART_TRANSPORT_USB_COM_PORT {
+            // DAP_UART_Transferで使っている間はCOMポートとの間で転送しない。戻ってきたら設定し直す
+            self.line_config = None;
+            return Ok(());
+        }
+        // ホストが設定したライン・コーディングが変わったらUARTに設定する。対応していない設定なら前の設定のまま
+        if let Some(line_config) = line_config(self.serial.line_coding()) {
+            if self.line_config != Some(line_config) {
+                uart.configure(&line_config).ok();
+                self.line_config = Some(line_config);
+            }
+        }
+        // ホストからのデータはUARTの送信バッファに空きがある分だけ読む。読まなければエンドポイントがNAKを返す
+        let mut buffer = [0u8; USB_COM_PORT_PACKET_SIZE];
+        let space = uart.tx_space().min(buffer.len());
+        if space > 0 {
+            match self.serial.read(&mut buffer[..space]) {
+                Ok(length) => {
+                    uart.write(&buffer[..length]);
+                }
+                Err(UsbError::WouldBlock) => {}
+                Err(err) => return Err(err),
+            }
+        }
+        // UARTから受信したデータをホストに送る
+        loop {
+            let (packet, offset, length) = match self.rx_packet.as_mut() {
+                Some(rx_packet) => rx_packet,
+                None => {
+                    if uart.rx_len() == 0 {
+                        return Ok(());
+                    }
+                    let mut packet = [0u8; USB_COM_PORT_PACKET_SIZE];
+                    let length = uart.read(&mut packet);
+                    self.rx_packet.insert((packet, 0, length))
+                }
+            };
+            match self.serial.write(&packet[*offset..*length]) {
+                Ok(written) => {
+                    *offset += written;
+                    if *offset == *length {
+                        self.rx_packet = None;
+                    }
+                }
+                Err(UsbError::WouldBlock) => return Ok(()),
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+/// ホストが設定したライン・コーディングをUARTの設定に変換する。UARTが対応していなければNoneを返す
+fn line_config(line_coding: &LineCoding) -> Option<UartLineConfig> {
+    let parity = match line_coding.parity_type() {
+        ParityType::None => UartParity::None,
+        ParityType::Odd => UartParity::Odd,
+        ParityType::Event => UartParity::Even,  // usbd-serial 0.1ではEvenがEventと綴られている
+        _ => return None,   // MarkとSpaceには対応しない
+    };
+    let stop_bits = match line_coding.stop_bits() {
+        StopBits::One => UartStopBits::One,
+        StopBits::Two => UartStopBits::Two,
+        StopBits::OnePointFive => return None,  // PL011は1.5ストップビットに対応しない
+    };
+    if !(5..=8).contains(&line_coding.data_bits()) {
+        return None;
+    }
+    Some(UartLineConfig {
+        baudrate: line_coding.data_rate(),
+        data_bits: line_coding.data_bits(),
+        parity,
+        stop_bits,
+    })
+}
+
+/// CMSIS-DAP interface over a pair of bulk endpoints. (CMSIS-DAP v2)
+///
+/// Up to `N` request packets can be outstanding. `N` must be at least 1, and is reported to the host by DAP_Info.
+/// A HID interface (CMSIS-DAP v1) sharing the same command processor can be added by `with_hid`,
+/// SWO trace capture with its streaming endpoint by `with_swo`,
+/// the target UART accessed by the DAP_UART commands by `with_uart`,
+/// and a CDC-ACM port bridged to the target UART by `with_usb_com_port`.
+pub struct CmsisDapInterface<'a, B: UsbBus, S: SwdIo, const N: usize = 4, W: SwoReceiver = NoSwo, U: UartIo = NoUart> {
+    interface: InterfaceNumber,
+    serial_string: StringIndex,
+    endpoints: DapEndpoints<'a, B, N>,
+    swo_ep: Option<EndpointIn<'a, B>>,
+    swo_packet: Option<Packet>,
+    hid: Option<HidInterface<'a, B, N>>,
+    usb_com_port: Option<UsbComPort<'a, B>>,
+    processor: DapProcessor<S, W, U>,
+}
+
+impl<'a, B: UsbBus, S: SwdIo, const N: usize> CmsisDapInterface<'a, B, S, N> {
+    pub fn new(alloc: &'a UsbBusAllocator<B>, max_packet_size: u16, swdio: S) -> Self {
         CmsisDapInterface {
             interface: alloc.interface(),       // インターフェース番号を確保
             serial_string: alloc.string(),      // インターフェース文字列の番号を確保
-            out_ep: alloc.bulk(max_packet_size),    // Bulk OUT エンドポイントを確保
-            in_ep: alloc.bulk(max_packet_size),     // Bulk IN エンドポイントを確保
-            response_buffer: [0u8; 64],         // レスポンス格納用バッファ
-            pending_response_bytes: None,       // 返信まちレスポンスバイト数 
+            endpoints: DapEndpoints::new(
+                alloc.bulk(max_packet_size),    // Bulk OUT エンドポイントを確保
+                alloc.bulk(max_packet_size),    // Bulk IN エンドポイントを確保
+                false,
+            ),
+            swo_ep: None,
+            swo_packet: None,
+            hid: None,
+            usb_com_port: None,
+            processor: DapProcessor::new(swdio).with_packet_count(N as u8), // CMSIS-DAPコマンドの処理部
         }
     }
+}
 
-    pub fn poll(&mut self) -> Result<()> {
-        // 未送信レスポンスがあるか？
-        if let Some(pending_response_bytes) = self.pending_response_bytes.as_ref() {
-            self.in_ep.write(&self.response_buffer[..*pending_response_bytes])?;
-            // 送信成功したのでクリア
-            self.pending_response_bytes = None;
+impl<'a, B: UsbBus, S: SwdIo, const N: usize, U: UartIo> CmsisDapInterface<'a, B, S, N, NoSwo, U> {
+    /// Captures SWO trace by `receiver`, and adds the bulk IN endpoint to stream the trace data.
+    pub fn with_swo<R: SwoReceiver>(
+        self,
+        alloc: &'a UsbBusAllocator<B>,
+        max_packet_size: u16,
+        receiver: R,
+    ) -> CmsisDapInterface<'a, B, S, N, R, U> {
+        CmsisDapInterface {
+            interface: self.interface,
+            serial_string: self.serial_string,
+            endpoints: self.endpoints,
+            swo_ep: Some(alloc.bulk(max_packet_size)),  // SWOトレース用の Bulk IN エンドポイントを確保
+            swo_packet: None,
+            hid: self.hid,
+            usb_com_port: self.usb_com_port,
+            processor: self.processor.with_swo(receiver, true),
         }
-        // コマンドを受信
-        let mut response_length = 0;
-        {
-            let mut response = &mut self.response_buffer[..];
-            // ホストからパケット受信
-            let mut request_buffer = [0u8; 64];
-            let request_length = self.out_ep.read(&mut request_buffer)?;
-            let mut request = &request_buffer[..request_length];
-            while request.len() > 0 {
-                match request[0] {
-                    0x00 => {   // DAP_Infoコマンド
-                        if request.len() >= 2 {
-                            // ID
-                            let response_bytes = match request[1] {
-                                0x01 => "vendor".as_bytes(),    // ベンダー名
-                                0x02 => "product".as_bytes(),   // プロダクト名
-                                0x03 => "serial".as_bytes(),    // シリアル番号
-                                0x04 => "2.0.0".as_bytes(),     // CMSIS-DAPバージョン
-                                0x09 => "1.0.0".as_bytes(),     // ファームウェアバージョン
-                                0xf0 => &[0x01, 0x00],          // Capabilities = SWD
-                                0xfe => &[0x01],                // 最大パケット数
-                                0xff => &[64, 0],               // 最大パケットサイズ
-                                _ => &[],                       // 未実装
-                            };
-                            // レスポンス・バッファに書き込み
-                            response[response_length + 0] = 0;
-                            response[response_length + 1] = response_bytes.len() as u8;
-                            response[response_length + 2..response_length + 2 + response_bytes.len()]
-                                .copy_from_slice(response_bytes);
-                            let response_length_inc = 2 + response_bytes.len();
-                            response_length += response_length_inc;
-                            response = &mut response[response_length_inc..];
-                            // リクエストの読み出し位置を更新
-                            request = &request[2..];
-                        }
-                    },
-                    _ => {
-                        // 未実装コマンド。無視する
-                        break;
-                    },
+    }
+}
+
+impl<'a, B: UsbBus, S: SwdIo, const N: usize, W: SwoReceiver> CmsisDapInterface<'a, B, S, N, W, NoUart> {
+    /// Adds a CDC-ACM port (USB COM port) bridged to the target UART given by `with_uart`.
+    ///
+    /// The UART follows the line coding set by the host while it is not used by DAP_UART_Transfer.
+    /// Call this after `with_hid` so that the interfaces are described in the order of their numbers.
+    pub fn with_usb_com_port(mut self, alloc: &'a UsbBusAllocator<B>) -> Self {
+        self.usb_com_port = Some(UsbComPort {
+            serial: SerialPort::new(alloc), // CDC-ACMの2つのインターフェースとエンドポイントを確保
+            line_config: None,
+            rx_packet: None,
+        });
+        self
+    }
+
+    /// Connects the target UART accessed by the DAP_UART commands to `io`.
+    pub fn with_uart<T: UartIo>(self, io: T) -> CmsisDapInterface<'a, B, S, N, W, T> {
+        let usb_com_port = self.usb_com_port.is_some();
+        CmsisDapInterface {
+            interface: self.interface,
+            serial_string: self.serial_string,
+            endpoints: self.endpoints,
+            swo_ep: self.swo_ep,
+            swo_packet: self.swo_packet,
+            hid: self.hid,
+            usb_com_port: self.usb_com_port,
+            processor: self.processor.with_uart(io, usb_com_port),
+        }
+    }
+}
+
+impl<'a, B: UsbBus, S: SwdIo, const N: usize, W: SwoReceiver, U: UartIo> CmsisDapInterface<'a, B, S, N, W, U> {
+    /// Adds a HID interface (CMSIS-DAP v1) for hosts which cannot use the bulk interface.
+    ///
+    /// v1 hosts find the probe by "CMSIS-DAP" in the product string of the device.
+    pub fn with_hid(mut self, alloc: &'a UsbBusAllocator<B>) -> Self {
+        self.hid = Some(HidInterface {
+            interface: alloc.interface(),       // HIDのインターフェース番号を確保
+            interface_string: alloc.string(),   // HIDのインターフェース文字列の番号を確保
+            endpoints: DapEndpoints::new(
+                alloc.interrupt(HID_REPORT_SIZE, HID_POLL_INTERVAL_MS),   // Interrupt OUT エンドポイントを確保
+                alloc.interrupt(HID_REPORT_SIZE, HID_POLL_INTERVAL_MS),   // Interrupt IN エンドポイントを確保
+                true,
+            ),
+        });
+        self
+    }
+
+    /// Sets the identity and capabilities of the probe reported by DAP_Info.
+    pub fn with_info(mut self, info: DapInfo) -> Self {
+        self.processor = self.processor.with_info(info);
+        self
+    }
+
+    /// Registers the target specific reset sequence run by DAP_ResetTarget.
+    pub fn with_reset_target(mut self, reset_target: ResetTargetFn<S>) -> Self {
+        self.processor = self.processor.with_reset_target(reset_target);
+        self
+    }
+
+    pub fn poll(&mut self) -> Result<()> {
+        // HIDインターフェースのリクエストも同じ処理部で処理する
+        let hid_result = match self.hid.as_mut() {
+            Some(hid) => hid.endpoints.poll(&mut self.processor),
+            None => Ok(()),
+        };
+        let result = self.endpoints.poll(&mut self.processor);
+        // SWOトレースのデータを受信し、ストリーミングする設定ならエンドポイントから送信する
+        self.processor.swo().poll();
+        let swo_result = self.send_swo_trace();
+        // ターゲットのUARTとバッファの間、バッファとCOMポートの間でデータを転送する
+        self.processor.uart().poll();
+        let usb_com_port_result = match self.usb_com_port.as_mut() {
+            Some(usb_com_port) => usb_com_port.poll(self.processor.uart()),
+            None => Ok(()),
+        };
+        result.and(hid_result).and(swo_result).and(usb_com_port_result)
+    }
+
+    fn send_swo_trace(&mut self) -> Result<()> {
+        let swo_ep = match self.swo_ep.as_ref() {
+            Some(swo_ep) => swo_ep,
+            None => return Ok(()),
+        };
+        let swo = self.processor.swo();
+        loop {
+            if self.swo_packet.is_none() {
+                if swo.transport() != SWO_TRANSPORT_STREAM || swo.is_empty() {
+                    return Ok(());
                 }
+                let mut packet = [0u8; PACKET_SIZE];
+                let length = swo.read(&mut packet);
+                self.swo_packet = Some((packet, length));
+            }
+            if let Some((packet, length)) = self.swo_packet.as_ref() {
+                swo_ep.write(&packet[..*length])?;
+                // 送信成功したので取り除く
+                self.swo_packet = None;
             }
         }
-        
-        if let Err(_) = self.in_ep.write(&self.response_buffer[..response_length]) {
-            // 送信できなかったので送信まち状態とする
-            self.pending_response_bytes = Some(response_length);
-        }
-        Ok(())
     }
 }
 
-impl<B: UsbBus> UsbClass<B> for CmsisDapInterface<'_, B> {
+impl<B: UsbBus, S: SwdIo, const N: usize, W: SwoReceiver, U: UartIo> UsbClass<B> for CmsisDapInterface<'_, B, S, N, W, U> {
     fn get_configuration_descriptors(&self, writer: &mut DescriptorWriter) -> Result<()> {
+        // 複合デバイスの機能ごとにIADを書き込む。CMSIS-DAPはインターフェース1個
+        writer.iad(self.interface, 1, USB_IF_CLASS_VENDOR, USB_IF_SUBCLASS_VENDOR, USB_IF_PROTOCOL_NONE)?;
         writer.interface_alt(   // インターフェースディスクリプタを書き込み
             self.interface,     // インターフェース番号
             DEFAULT_ALTERNATE_SETTING,  // このコンフィグレーションのデフォルト・インターフェース
@@ -136,15 +481,54 @@ impl<B: UsbBus> UsbClass<B> for CmsisDapInterface<'_, B> {
             USB_IF_PROTOCOL_NONE,    // プロトコルなし (0x00)
             Some(self.serial_string),  // インターフェース文字列のインデックス 
         )?;
-        writer.endpoint(&self.out_ep)?; // Bulk OUT エンドポイントディスクリプタを書き込み
-        writer.endpoint(&self.in_ep)?;  // Bulk IN エンドポイントディスクリプタを書き込み
+        writer.endpoint(&self.endpoints.out_ep)?;   // Bulk OUT エンドポイントディスクリプタを書き込み
+        writer.endpoint(&self.endpoints.in_ep)?;    // Bulk IN エンドポイントディスクリプタを書き込み
+        if let Some(swo_ep) = self.swo_ep.as_ref() {
+            writer.endpoint(swo_ep)?;   // SWOトレース用の Bulk IN エンドポイントディスクリプタを書き込み
+        }
+
+        if let Some(hid) = self.hid.as_ref() {
+            writer.iad(hid.interface, 1, USB_IF_CLASS_HID, USB_IF_SUBCLASS_NONE, USB_IF_PROTOCOL_NONE)?;
+            writer.interface_alt(   // HIDのインターフェースディスクリプタを書き込み
+                hid.interface,
+                DEFAULT_ALTERNATE_SETTING,
+                USB_IF_CLASS_HID,       // HIDクラス (0x03)
+                USB_IF_SUBCLASS_NONE,   // ブートインターフェースではない (0x00)
+                USB_IF_PROTOCOL_NONE,   // プロトコルなし (0x00)
+                Some(hid.interface_string),
+            )?;
+            writer.write(HID_DESCRIPTOR_TYPE_HID, &hid_descriptor()[2..])?; // HIDディスクリプタを書き込み
+            writer.endpoint(&hid.endpoints.out_ep)?;    // Interrupt OUT エンドポイントディスクリプタを書き込み
+            writer.endpoint(&hid.endpoints.in_ep)?;     // Interrupt IN エンドポイントディスクリプタを書き込み
+        }
+
+        if let Some(usb_com_port) = self.usb_com_port.as_ref() {
+            // CDC-ACMのIAD、インターフェース、エンドポイントのディスクリプタを書き込み
+            usb_com_port.serial.get_configuration_descriptors(writer)?;
+        }
 
         Ok(())
     }
+
+    fn reset(&mut self) {
+        if let Some(usb_com_port) = self.usb_com_port.as_mut() {
+            usb_com_port.serial.reset();
+            usb_com_port.rx_packet = None;
+        }
+    }
+
+    fn endpoint_in_complete(&mut self, addr: EndpointAddress) {
+        if let Some(usb_com_port) = self.usb_com_port.as_mut() {
+            // COMポートの送信バッファに残っているデータを続けて送る
+            usb_com_port.serial.endpoint_in_complete(addr);
+        }
+    }
     fn get_string(&self, index: StringIndex, lang_id: u16) -> Option<&str> {
         let _ = lang_id;
         if index == self.serial_string {    // インターフェース文字列に対する要求？
             Some("CMSIS-DAP interface")     // インターフェース文字列を返す
+        } else if self.hid.as_ref().map(|hid| hid.interface_string) == Some(index) {
+            Some("CMSIS-DAP v1 interface")  // HIDのインターフェース文字列を返す
         } else {
             None
         }
@@ -170,12 +554,30 @@ impl<B: UsbBus> UsbClass<B> for CmsisDapInterface<'_, B> {
 
     fn control_in(&mut self, xfer: ControlIn<B>) {
         let request = xfer.request();
+        if let Some(hid) = self.hid.as_ref() {
+            if request.recipient == Recipient::Interface && request.index == u8::from(hid.interface) as u16 {
+                // HIDのインターフェースに対する要求
+                match (request.request_type, request.request) {
+                    (RequestType::Standard, Request::GET_DESCRIPTOR) => match (request.value >> 8) as u8 {
+                        HID_DESCRIPTOR_TYPE_REPORT => xfer.accept_with_static(HID_REPORT_DESCRIPTOR).ok(),
+                        HID_DESCRIPTOR_TYPE_HID => xfer.accept_with(&hid_descriptor()).ok(),
+                        _ => None,
+                    },
+                    // 入力レポートはInterrupt INエンドポイントで返すので、コントロール転送では空のレポートを返す
+                    (RequestType::Class, HID_REQUEST_GET_REPORT) => xfer.accept_with(&[0u8; PACKET_SIZE]).ok(),
+                    _ => None,
+                };
+                return;
+            }
+        }
         if request.request_type == RequestType::Vendor
             && request.request == MS_VENDOR_CODE
             && request.value == 0
             && request.index == 7
         {
             // Request to retrieve MS OS 2.0 Descriptor Set.
+            // Only the function subset of the CMSIS-DAP interface has the WinUSB compatible ID,
+            // so Windows loads its inbox drivers for the HID and CDC-ACM functions.
             let interface_number = self.interface;
             xfer.accept(|buffer| {
                 write_descriptor_set(buffer, 0x06030000, |buffer| {
@@ -211,10 +613,46 @@ impl<B: UsbBus> UsbClass<B> for CmsisDapInterface<'_, B> {
                 })
             })
             .unwrap();
+        } else if let Some(usb_com_port) = self.usb_com_port.as_mut() {
+            // CDC-ACMのインターフェースに対する要求 (GET_LINE_CODING) はSerialPortが処理する
+            usb_com_port.serial.control_in(xfer);
+        }
+    }
+
+    fn control_out(&mut self, xfer: ControlOut<B>) {
+        let request = xfer.request();
+        if let Some(hid) = self.hid.as_ref() {
+            if request.recipient == Recipient::Interface
+                && request.index == u8::from(hid.interface) as u16
+                && request.request_type == RequestType::Class
+                && request.request == HID_REQUEST_SET_IDLE
+            {
+                // レポートは要求があったときだけ送信するので、SET_IDLEは受け付けるだけ
+                xfer.accept().ok();
+                return;
+            }
+        }
+        if let Some(usb_com_port) = self.usb_com_port.as_mut() {
+            // CDC-ACMのインターフェースに対する要求 (SET_LINE_CODINGなど) はSerialPortが処理する
+            usb_com_port.serial.control_out(xfer);
         }
     }
 }
 
+fn hid_descriptor() -> [u8; 9] {
+    let report_descriptor_length = HID_REPORT_DESCRIPTOR.len() as u16;
+    [
+        9,                          // bLength
+        HID_DESCRIPTOR_TYPE_HID,    // bDescriptorType
+        0x11, 0x01,                 // bcdHID = 1.11
+        0x00,                       // bCountryCode
+        1,                          // bNumDescriptors
+        HID_DESCRIPTOR_TYPE_REPORT, // bDescriptorType
+        u16_lo(report_descriptor_length),   // wDescriptorLength
+        u16_hi(report_descriptor_length),
+    ]
+}
+
 
 fn write_descriptor_set(
     buffer: &mut [u8],
@@ -344,11 +782,83 @@ const fn u32_hi(v: u32) -> u16 {
     (v >> 16) as u16
 }
 
-#[derive(Debug, PartialEq)]
-pub enum DapError {
-    InvalidCommand,
-    InvalidDapInfoId,
-    SwdError(u8),
-    InternalError,
-    ExceedRetryCount,
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim_target::SimulatedTarget;
+    use std::collections::VecDeque;
+    use std::sync::{Arc, Mutex};
+    use usb_device::bus::PollResult;
+    use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
+    use usb_device::UsbDirection;
+
+    #[derive(Default)]
+    struct Packets {
+        out: VecDeque<Vec<u8>>,
+        sent: Vec<Vec<u8>>,
+    }
+
+    /// `UsbBus` which passes the packets of a single pair of bulk endpoints.
+    struct MockBus(Arc<Mutex<Packets>>);
+
+    impl UsbBus for MockBus {
+        fn alloc_ep(
+            &mut self,
+            ep_dir: UsbDirection,
+            ep_addr: Option<EndpointAddress>,
+            _ep_type: EndpointType,
+            _max_packet_size: u16,
+            _interval: u8,
+        ) -> Result<EndpointAddress> {
+            // 制御エンドポイント以外は1番だけを使う
+            Ok(ep_addr.unwrap_or_else(|| EndpointAddress::from_parts(1, ep_dir)))
+        }
+        fn enable(&mut self) {}
+        fn reset(&self) {}
+        fn set_device_address(&self, _addr: u8) {}
+        fn write(&self, _ep_addr: EndpointAddress, buf: &[u8]) -> Result<usize> {
+            self.0.lock().unwrap().sent.push(buf.to_vec());
+            Ok(buf.len())
+        }
+        fn read(&self, _ep_addr: EndpointAddress, buf: &mut [u8]) -> Result<usize> {
+            let packet = self.0.lock().unwrap().out.pop_front().ok_or(UsbError::WouldBlock)?;
+            buf[..packet.len()].copy_from_slice(&packet);
+            Ok(packet.len())
+        }
+        fn set_stalled(&self, _ep_addr: EndpointAddress, _stalled: bool) {}
+        fn is_stalled(&self, _ep_addr: EndpointAddress) -> bool {
+            false
+        }
+        fn suspend(&self) {}
+        fn resume(&self) {}
+        fn poll(&self) -> PollResult {
+            PollResult::None
+        }
+    }
+
+    #[test]
+    fn transfer_abort_stops_the_rest_of_the_batch_and_keeps_the_next_command() {
+        let packets = Arc::new(Mutex::new(Packets::default()));
+        let alloc = UsbBusAllocator::new(MockBus(packets.clone()));
+        let mut dap = CmsisDapInterface::<_, _, 4>::new(&alloc, PACKET_SIZE as u16, SimulatedTarget::<256>::new(0x2000_0000));
+        // エンドポイントはUsbDeviceを構築すると使えるようになる
+        let _usb_device = UsbDeviceBuilder::new(&alloc, UsbVidPid(0x6666, 0x4444)).build();
+        packets.lock().unwrap().out.push_back(vec![0x02, 0x01]);
+        dap.poll().ok();
+        assert_eq!(core::mem::take(&mut packets.lock().unwrap().sent), [[0x02, 0x01]]);
+        {
+            let mut packets = packets.lock().unwrap();
+            // DP IDCODEを14ワード読み出すDAP_TransferBlockを2つ実行する
+            packets.out.push_back(vec![0x7f, 2, 0x06, 0, 14, 0, 0x02, 0x06, 0, 14, 0, 0x02]);
+            packets.out.push_back(vec![DAP_TRANSFER_ABORT]);
+            packets.out.push_back(vec![0x09, 0, 0]);
+        }
+        dap.poll().ok();
+        // 中止した後の転送も中止して、DAP_TransferAbortの後のDAP_Delayは実行する
+        assert_eq!(
+            packets.lock().unwrap().sent,
+            [vec![0x7f, 2, 0x06, 0, 0, 0x01, 0x06, 0, 0, 0x01], vec![0x09, 0x00]]
+        );
+        assert!(packets.lock().unwrap().out.is_empty());
+    }
 }
@@ -0,0 +1,65 @@
+// Copyright 2023 Kenta Ida
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+// DAP_Info capabilities (Info0)
+pub const DAP_CAP_SWD: u16 = 1 << 0;
+pub const DAP_CAP_JTAG: u16 = 1 << 1;
+pub const DAP_CAP_SWO_UART: u16 = 1 << 2;
+pub const DAP_CAP_SWO_MANCHESTER: u16 = 1 << 3;
+pub const DAP_CAP_ATOMIC_COMMANDS: u16 = 1 << 4;
+pub const DAP_CAP_TEST_DOMAIN_TIMER: u16 = 1 << 5;
+pub const DAP_CAP_SWO_STREAMING_TRACE: u16 = 1 << 6;
+pub const DAP_CAP_UART_COMMUNICATION_PORT: u16 = 1 << 7;
+// DAP_Info capabilities (Info1)
+pub const DAP_CAP_UART_USB_COM_PORT: u16 = 1 << 8;
+
+/// Identity and capabilities of the probe reported by DAP_Info.
+///
+/// Empty strings and zero values are reported as not available.
+#[derive(Clone, Copy)]
+pub struct DapInfo {
+    pub vendor: &'static str,
+    pub product: &'static str,
+    /// Must be the same as the serial number string of the USB device.
+    pub serial_number: &'static str,
+    pub target_device_vendor: &'static str,
+    pub target_device_name: &'static str,
+    pub target_board_vendor: &'static str,
+    pub target_board_name: &'static str,
+    pub product_firmware_version: &'static str,
+    /// `DAP_CAP_*` bits. The JTAG bit is reported only if the `SwdIo` supports JTAG,
+    /// the SWO streaming trace bit only if the transport streams the trace data,
+    /// and the UART USB COM port bit only if the transport has the COM port.
+    pub capabilities: u16,
+    pub test_domain_timer_hz: u32,
+}
+
+impl Default for DapInfo {
+    fn default() -> Self {
+        Self {
+            vendor: "",
+            product: "",
+            serial_number: "",
+            target_device_vendor: "",
+            target_device_name: "",
+            target_board_vendor: "",
+            target_board_name: "",
+            product_firmware_version: env!("CARGO_PKG_VERSION"),
+            capabilities: DAP_CAP_SWD | DAP_CAP_JTAG | DAP_CAP_ATOMIC_COMMANDS,
+            test_domain_timer_hz: 0,
+        }
+    }
+}
@@ -0,0 +1,1544 @@
+// Copyright 2023 Kenta Ida
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::dap_info::*;
+use crate::dap_uart::*;
+use crate::jtagio::*;
+use crate::swdio::*;
+use crate::swo::*;
+use crate::target_reset::ResetTargetFn;
+
+const DAP_OK: u8 = 0x00;
+const DAP_ERROR: u8 = 0xff;
+const DAP_INVALID: u8 = 0xff;
+
+// CMSIS-DAPのプロトコルバージョン
+const DAP_PROTOCOL_VERSION: &str = "2.1.0";
+const DAP_PACKET_SIZE: u16 = 64;
+
+const DAP_PORT_DISABLED: u8 = 0x00;
+const DAP_PORT_DEFAULT: u8 = 0x00;
+const DAP_PORT_SWD: u8 = 0x01;
+const DAP_PORT_JTAG: u8 = 0x02;
+const DAP_PORT_FAILED: u8 = 0x00;
+
+const SWJ_PINS_MAX_WAIT_US: u32 = 3_000_000;
+
+// DAP_SWD_Sequence sequence info bits
+const SWD_SEQUENCE_CLK: u8 = 0x3f;
+const SWD_SEQUENCE_DIN: u8 = 1 << 7;
+
+// DAP_JTAG_Sequence sequence info bits
+const JTAG_SEQUENCE_TCK: u8 = 0x3f;
+const JTAG_SEQUENCE_TMS: u8 = 1 << 6;
+const JTAG_SEQUENCE_TDO: u8 = 1 << 7;
+
+#[derive(Debug, PartialEq)]
+pub enum DapError {
+    InvalidCommand,
+    InvalidDapInfoId,
+    SwdError(u8),
+    InternalError,
+    ExceedRetryCount,
+}
+
+/// State of the posted read and the pending write check across the transfers in one DAP_Transfer command.
+#[derive(Default)]
+struct TransferState {
+    post_read: bool,
+    post_read_ap: bool,
+    check_write: bool,
+}
+
+/// Bounds checked writer of a response packet.
+///
+/// Writing beyond the end of the buffer fails with `DapError::InternalError` instead of panicking.
+struct ResponseWriter<'a> {
+    buffer: &'a mut [u8],
+    length: usize,
+}
+
+impl<'a> ResponseWriter<'a> {
+    fn new(buffer: &'a mut [u8]) -> Self {
+        Self { buffer, length: 0 }
+    }
+
+    fn len(&self) -> usize {
+        self.length
+    }
+
+    fn remaining(&self) -> usize {
+        self.buffer.len() - self.length
+    }
+
+    fn truncate(&mut self, length: usize) {
+        self.length = self.length.min(length);
+    }
+
+    /// Appends `length` bytes and returns them to be filled by the caller.
+    fn reserve(&mut self, length: usize) -> Result<&mut [u8], DapError> {
+        if length > self.remaining() {
+            return Err(DapError::InternalError);
+        }
+        let start = self.length;
+        self.length += length;
+        Ok(&mut self.buffer[start..self.length])
+    }
+
+    fn extend(&mut self, bytes: &[u8]) -> Result<(), DapError> {
+        self.reserve(bytes.len())?.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    fn push(&mut self, value: u8) -> Result<(), DapError> {
+        self.extend(&[value])
+    }
+
+    fn push_u32(&mut self, value: u32) -> Result<(), DapError> {
+        self.extend(&value.to_le_bytes())
+    }
+
+    /// Overwrites the bytes already written at `offset`.
+    fn set(&mut self, offset: usize, bytes: &[u8]) -> Result<(), DapError> {
+        let end = offset + bytes.len();
+        if end > self.length {
+            return Err(DapError::InternalError);
+        }
+        self.buffer[offset..end].copy_from_slice(bytes);
+        Ok(())
+    }
+}
+
+/// Transport independent CMSIS-DAP command processor.
+///
+/// Takes the bytes of a request packet and writes the bytes of the corresponding response packet.
+///
+/// SWO trace is captured by the `SwoReceiver` given by `with_swo`,
+/// and the target UART is connected to the `UartIo` given by `with_uart`.
+pub struct DapProcessor<S: SwdIo, W: SwoReceiver = NoSwo, U: UartIo = NoUart> {
+    swdio: S,
+    config: SwdIoConfig,
+    jtag: JtagConfig,
+    port: u8,
+    match_mask: u32,
+    // 現在選択しているJTAGのIR
+    jtag_ir: u32,
+    reset_target: Option<ResetTargetFn<S>>,
+    packet_count: u8,
+    info: DapInfo,
+    swo: SwoTrace<W>,
+    uart: DapUart<U>,
+}
+
+impl<S: SwdIo> DapProcessor<S> {
+    pub fn new(swdio: S) -> Self {
+        Self {
+            swdio,
+            config: SwdIoConfig::default(),
+            jtag: JtagConfig::default(),
+            port: DAP_PORT_DISABLED,
+            match_mask: 0xffff_ffff,
+            jtag_ir: 0,
+            reset_target: None,
+            packet_count: 1,
+            info: DapInfo::default(),
+            swo: SwoTrace::new(NoSwo, false),
+            uart: DapUart::new(NoUart, false),
+        }
+    }
+}
+
+impl<S: SwdIo, W: SwoReceiver, U: UartIo> DapProcessor<S, W, U> {
+    /// Captures SWO trace by `receiver`.
+    ///
+    /// `streaming` tells whether the transport streams the trace data by itself. (SWO transport 2)
+    pub fn with_swo<R: SwoReceiver>(self, receiver: R, streaming: bool) -> DapProcessor<S, R, U> {
+        DapProcessor {
+            swdio: self.swdio,
+            config: self.config,
+            jtag: self.jtag,
+            port: self.port,
+            match_mask: self.match_mask,
+            jtag_ir: self.jtag_ir,
+            reset_target: self.reset_target,
+            packet_count: self.packet_count,
+            info: self.info,
+            swo: SwoTrace::new(receiver, streaming),
+            uart: self.uart,
+        }
+    }
+
+    /// Connects the target UART to `io`.
+    ///
+    /// `usb_com_port` tells whether the transport bridges the UART to a USB COM port. (UART transport 1)
+    pub fn with_uart<T: UartIo>(self, io: T, usb_com_port: bool) -> DapProcessor<S, W, T> {
+        DapProcessor {
+            swdio: self.swdio,
+            config: self.config,
+            jtag: self.jtag,
+            port: self.port,
+            match_mask: self.match_mask,
+            jtag_ir: self.jtag_ir,
+            reset_target: self.reset_target,
+            packet_count: self.packet_count,
+            info: self.info,
+            swo: self.swo,
+            uart: DapUart::new(io, usb_com_port),
+        }
+    }
+
+    /// Sets the identity and capabilities of the probe reported by DAP_Info.
+    pub fn with_info(mut self, info: DapInfo) -> Self {
+        self.info = info;
+        self
+    }
+
+    /// Sets the number of packets the transport can buffer, reported by DAP_Info.
+    pub fn with_packet_count(mut self, packet_count: u8) -> Self {
+        self.packet_count = packet_count;
+        self
+    }
+
+    /// Registers the target specific reset sequence run by DAP_ResetTarget.
+    pub fn with_reset_target(mut self, reset_target: ResetTargetFn<S>) -> Self {
+        self.reset_target = Some(reset_target);
+        self
+    }
+
+    pub fn swdio(&mut self) -> &mut S {
+        &mut self.swdio
+    }
+
+    pub fn config(&self) -> &SwdIoConfig {
+        &self.config
+    }
+
+    pub fn jtag_config(&self) -> &JtagConfig {
+        &self.jtag
+    }
+
+    pub fn swo(&mut self) -> &mut SwoTrace<W> {
+        &mut self.swo
+    }
+
+    pub fn uart(&mut self) -> &mut DapUart<U> {
+        &mut self.uart
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.port != DAP_PORT_DISABLED
+    }
+
+    /// Processes the commands in `request` and returns the number of bytes written to `response`.
+    pub fn process(&mut self, request: &[u8], response: &mut [u8]) -> usize {
+        self.process_with_abort(request, response, &mut || false)
+    }
+
+    /// Same as `process`, but polls `abort` between the transfers of DAP_Transfer/DAP_TransferBlock
+    /// and cancels the rest of the transfers when it returns true. (DAP_TransferAbort)
+    pub fn process_with_abort(
+        &mut self,
+        request: &[u8],
+        response: &mut [u8],
+        abort: &mut dyn FnMut() -> bool,
+    ) -> usize {
+        let mut request = request;
+        let mut response = ResponseWriter::new(response);
+        while !request.is_empty() {
+            match self.execute_command_or_invalid(request, &mut response, abort) {
+                // リクエストの読み出し位置を更新
+                Some(request_length) => request = &request[request_length..],
+                // コマンドの長さが分からないので、以降のコマンドは処理しない
+                None => break,
+            }
+        }
+        response.len()
+    }
+
+    /// Same as `process_with_abort`, but executes only the first command in `request`.
+    /// (and the commands in it if it is DAP_ExecuteCommands)
+    ///
+    /// Used for the transport which pads the request packets, e.g. HID reports,
+    /// where the bytes after the command are not commands.
+    pub fn process_command_with_abort(
+        &mut self,
+        request: &[u8],
+        response: &mut [u8],
+        abort: &mut dyn FnMut() -> bool,
+    ) -> usize {
+        let mut response = ResponseWriter::new(response);
+        if !request.is_empty() {
+            self.execute_command_or_invalid(request, &mut response, abort);
+        }
+        response.len()
+    }
+
+    /// Executes the command at the head of `request` and returns its length.
+    ///
+    /// Responds with DAP_Invalid and returns None if the command is unknown or broken.
+    fn execute_command_or_invalid(
+        &mut self,
+        request: &[u8],
+        response: &mut ResponseWriter,
+        abort: &mut dyn FnMut() -> bool,
+    ) -> Option<usize> {
+        let response_length = response.len();
+        match self.execute_command(request, response, abort) {
+            Ok(request_length) => Some(request_length),
+            Err(_) => {
+                // 未実装コマンドか不正なリクエスト。途中まで書いたレスポンスは捨ててDAP_Invalidを返す
+                response.truncate(response_length);
+                response.push(DAP_INVALID).ok();
+                None
+            }
+        }
+    }
+
+    /// Executes the command at the head of `request`.
+    fn execute_command(
+        &mut self,
+        request: &[u8],
+        response: &mut ResponseWriter,
+        abort: &mut dyn FnMut() -> bool,
+    ) -> Result<usize, DapError> {
+        match request[0] {
+            0x00 => self.dap_info(request, response), // DAP_Infoコマンド
+            0x02 => self.dap_connect(request, response), // DAP_Connectコマンド
+            0x03 => self.dap_disconnect(request, response), // DAP_Disconnectコマンド
+            0x04 => self.dap_transfer_configure(request, response), // DAP_TransferConfigureコマンド
+            0x05 => self.dap_transfer(request, response, abort), // DAP_Transferコマンド
+            0x06 => self.dap_transfer_block(request, response, abort), // DAP_TransferBlockコマンド
+            0x07 => self.dap_transfer_abort(request, response), // DAP_TransferAbortコマンド
+            0x08 => self.dap_write_abort(request, response), // DAP_WriteABORTコマンド
+            0x09 => self.dap_delay(request, response), // DAP_Delayコマンド
+            0x0a => self.dap_reset_target(request, response), // DAP_ResetTargetコマンド
+            0x10 => self.dap_swj_pins(request, response), // DAP_SWJ_Pinsコマンド
+            0x11 => self.dap_swj_clock(request, response), // DAP_SWJ_Clockコマンド
+            0x12 => self.dap_swj_sequence(request, response), // DAP_SWJ_Sequenceコマンド
+            0x13 => self.dap_swd_configure(request, response), // DAP_SWD_Configureコマンド
+            0x14 => self.dap_jtag_sequence(request, response), // DAP_JTAG_Sequenceコマンド
+            0x15 => self.dap_jtag_configure(request, response), // DAP_JTAG_Configureコマンド
+            0x16 => self.dap_jtag_idcode(request, response), // DAP_JTAG_IDCODEコマンド
+            0x17 => self.dap_swo_transport(request, response), // DAP_SWO_Transportコマンド
+            0x18 => self.dap_swo_mode(request, response), // DAP_SWO_Modeコマンド
+            0x19 => self.dap_swo_baudrate(request, response), // DAP_SWO_Baudrateコマンド
+            0x1a => self.dap_swo_control(request, response), // DAP_SWO_Controlコマンド
+            0x1b => self.dap_swo_status(request, response), // DAP_SWO_Statusコマンド
+            0x1c => self.dap_swo_data(request, response), // DAP_SWO_Dataコマンド
+            0x1d => self.dap_swd_sequence(request, response), // DAP_SWD_Sequenceコマンド
+            0x1e => self.dap_swo_extended_status(request, response), // DAP_SWO_ExtendedStatusコマンド
+            0x1f => self.dap_uart_transport(request, response), // DAP_UART_Transportコマンド
+            0x20 => self.dap_uart_configure(request, response), // DAP_UART_Configureコマンド
+            0x21 => self.dap_uart_control(request, response), // DAP_UART_Controlコマンド
+            0x22 => self.dap_uart_status(request, response), // DAP_UART_Statusコマンド
+            0x23 => self.dap_uart_transfer(request, response), // DAP_UART_Transferコマンド
+            0x7e => self.dap_execute_commands(request, response, abort), // DAP_QueueCommandsコマンド
+            0x7f => self.dap_execute_commands(request, response, abort), // DAP_ExecuteCommandsコマンド
+            _ => Err(DapError::InvalidCommand),
+        }
+    }
+
+    /// DAP_ExecuteCommands (0x7F), DAP_QueueCommands (0x7E)
+    ///
+    /// The transport holds the packets of DAP_QueueCommands until a packet with other command arrives,
+    /// so both of them are executed in the same way here and answered as DAP_ExecuteCommands.
+    fn dap_execute_commands(
+        &mut self,
+        request: &[u8],
+        response: &mut ResponseWriter,
+        abort: &mut dyn FnMut() -> bool,
+    ) -> Result<usize, DapError> {
+        if request.len() < 2 {
+            return Err(DapError::InvalidCommand);
+        }
+        let command_count = request[1] as usize;
+        response.extend(&[0x7f, request[1]])?;
+        let mut request_offset = 2;
+        for _ in 0..command_count {
+            // 入れ子にはできない
+            match request.get(request_offset) {
+                None | Some(0x7e) | Some(0x7f) => return Err(DapError::InvalidCommand),
+                _ => {}
+            }
+            request_offset += self.execute_command(&request[request_offset..], response, abort)?;
+        }
+        Ok(request_offset)
+    }
+
+    /// DAP_TransferAbort (0x07)
+    ///
+    /// The transport drops DAP_TransferAbort packets without a response and aborts the transfer in progress.
+    /// Only the one in DAP_ExecuteCommands comes here, and its command ID is returned
+    /// so that the number of responses matches the number of commands.
+    fn dap_transfer_abort(&mut self, _request: &[u8], response: &mut ResponseWriter) -> Result<usize, DapError> {
+        response.push(0x07)?;
+        Ok(1)
+    }
+
+    /// DAP_Info (0x00)
+    fn dap_info(&mut self, request: &[u8], response: &mut ResponseWriter) -> Result<usize, DapError> {
+        if request.len() < 2 {
+            return Err(DapError::InvalidCommand);
+        }
+        let info = self.info;
+        response.push(0x00)?;
+        // ID
+        match request[1] {
+            0x01 => push_info_string(response, info.vendor)?,                   // ベンダー名
+            0x02 => push_info_string(response, info.product)?,                  // プロダクト名
+            0x03 => push_info_string(response, info.serial_number)?,            // シリアル番号
+            0x04 => push_info_string(response, DAP_PROTOCOL_VERSION)?,          // CMSIS-DAPバージョン
+            0x05 => push_info_string(response, info.target_device_vendor)?,     // ターゲットデバイスのベンダー名
+            0x06 => push_info_string(response, info.target_device_name)?,       // ターゲットデバイス名
+            0x07 => push_info_string(response, info.target_board_vendor)?,      // ターゲットボードのベンダー名
+            0x08 => push_info_string(response, info.target_board_name)?,        // ターゲットボード名
+            0x09 => push_info_string(response, info.product_firmware_version)?, // ファームウェアバージョン
+            0xf0 => {
+                // Capabilities。JTAGはSwdIoが対応している場合のみ
+                let mut capabilities = info.capabilities;
+                if self.swdio.jtag().is_none() {
+                    capabilities &= !DAP_CAP_JTAG;
+                }
+                // SWOのストリーミングはトランスポートが対応している場合のみ
+                if !self.swo.is_streaming() {
+                    capabilities &= !DAP_CAP_SWO_STREAMING_TRACE;
+                }
+                // UARTのUSB COMポートはトランスポートが対応している場合のみ
+                if !self.uart.has_usb_com_port() {
+                    capabilities &= !DAP_CAP_UART_USB_COM_PORT;
+                }
+                let capabilities = capabilities.to_le_bytes();
+                if capabilities[1] == 0 {
+                    response.extend(&[1, capabilities[0]])?;
+                } else {
+                    response.extend(&[2, capabilities[0], capabilities[1]])?;
+                }
+            }
+            0xf1 => push_info_u32(response, info.test_domain_timer_hz)?,   // テスト・ドメイン・タイマーの周波数
+            0xfb | 0xfc => {
+                // UART受信バッファサイズ、UART送信バッファサイズ
+                let uart_buffer_size = match request[1] {
+                    _ if info.capabilities & DAP_CAP_UART_COMMUNICATION_PORT == 0 => 0,
+                    0xfb => UART_RX_BUFFER_SIZE as u32,
+                    _ => UART_TX_BUFFER_SIZE as u32,
+                };
+                push_info_u32(response, uart_buffer_size)?;
+            }
+            0xfd => {
+                // SWOトレースバッファサイズ
+                let swo_buffer_size = if info.capabilities & (DAP_CAP_SWO_UART | DAP_CAP_SWO_MANCHESTER) != 0 {
+                    SWO_BUFFER_SIZE as u32
+                } else {
+                    0
+                };
+                push_info_u32(response, swo_buffer_size)?;
+            }
+            0xfe => response.extend(&[1, self.packet_count])?,             // 最大パケット数
+            0xff => {
+                // 最大パケットサイズ
+                response.push(2)?;
+                response.extend(&DAP_PACKET_SIZE.to_le_bytes())?;
+            }
+            _ => response.push(0)?,                                         // 未実装
+        }
+        Ok(2)
+    }
+
+    /// DAP_Connect (0x02)
+    fn dap_connect(&mut self, request: &[u8], response: &mut ResponseWriter) -> Result<usize, DapError> {
+        if request.len() < 2 {
+            return Err(DapError::InvalidCommand);
+        }
+        let port = match request[1] {
+            // デフォルトはSWD
+            DAP_PORT_DEFAULT | DAP_PORT_SWD => DAP_PORT_SWD,
+            DAP_PORT_JTAG if self.swdio.jtag().is_some() => DAP_PORT_JTAG,
+            // 未サポートのポート
+            _ => DAP_PORT_FAILED,
+        };
+        if port != DAP_PORT_FAILED {
+            // 一旦切断してから接続しなおす
+            self.disconnect_port();
+            match port {
+                DAP_PORT_SWD => {
+                    self.swdio.connect();
+                    self.swdio.enable_output();
+                }
+                _ => {
+                    if let Some(jtagio) = self.swdio.jtag() {
+                        jtagio.jtag_connect();
+                    }
+                }
+            }
+            self.port = port;
+        }
+        response.extend(&[0x02, port])?;
+        Ok(2)
+    }
+
+    /// DAP_Disconnect (0x03)
+    fn dap_disconnect(&mut self, _request: &[u8], response: &mut ResponseWriter) -> Result<usize, DapError> {
+        self.disconnect_port();
+        response.extend(&[0x03, DAP_OK])?;
+        Ok(1)
+    }
+
+    fn disconnect_port(&mut self) {
+        match self.port {
+            DAP_PORT_SWD => {
+                self.swdio.disable_output();
+                self.swdio.disconnect();
+            }
+            DAP_PORT_JTAG => {
+                if let Some(jtagio) = self.swdio.jtag() {
+                    jtagio.jtag_disconnect();
+                }
+            }
+            _ => {}
+        }
+        self.port = DAP_PORT_DISABLED;
+    }
+
+    /// DAP_TransferConfigure (0x04)
+    fn dap_transfer_configure(&mut self, request: &[u8], response: &mut ResponseWriter) -> Result<usize, DapError> {
+        if request.len() < 6 {
+            return Err(DapError::InvalidCommand);
+        }
+        self.config.idle_cycles = request[1] as u32;
+        self.config.wait_retry = u16::from_le_bytes([request[2], request[3]]) as u32;
+        self.config.match_retry = u16::from_le_bytes([request[4], request[5]]) as u32;
+        response.extend(&[0x04, DAP_OK])?;
+        Ok(6)
+    }
+
+    /// DAP_Transfer (0x05)
+    fn dap_transfer(
+        &mut self,
+        request: &[u8],
+        response: &mut ResponseWriter,
+        abort: &mut dyn FnMut() -> bool,
+    ) -> Result<usize, DapError> {
+        // 途中で転送を打ち切ってもリクエスト全体を読み飛ばせるよう、先に長さを確定させる
+        let request_length = transfer_request_length(request)?;
+        // request[1]はDAP Index。JTAGのみ使う
+        let header_offset = response.len();
+        response.extend(&[0x05, 0, 0])?;
+        if !self.select_jtag_device(request[1]) {
+            return Ok(request_length);
+        }
+        let transfer_count = request[2] as usize;
+        let mut request_offset = 3;
+        let mut state = TransferState::default();
+        let mut response_count = 0;
+        let mut response_value = 0;
+        while response_count < transfer_count {
+            if abort() {
+                // 残りの転送を中止する
+                break;
+            }
+            let transfer_request = request[request_offset];
+            request_offset += 1;
+            // ライトデータもしくは一致待ちの値
+            let value = if transfer_request & DAP_TRANSFER_RNW == 0
+                || transfer_request & DAP_TRANSFER_MATCH_VALUE != 0
+            {
+                request_offset += 4;
+                read_u32(&request[request_offset - 4..])
+            } else {
+                0
+            };
+            match self.transfer_one(transfer_request, value, &mut state, response) {
+                Ok(()) => response_value = DAP_TRANSFER_OK,
+                // レスポンス・バッファに収まらない
+                Err(DapError::InternalError) => return Err(DapError::InternalError),
+                Err(err) => {
+                    response_value = transfer_status(err);
+                    break;
+                }
+            }
+            response_count += 1;
+        }
+
+        if response_value == DAP_TRANSFER_OK {
+            if state.post_read {
+                // 最後のリードの結果をRDBUFFから読み出す
+                match self.transfer_with_retry(DP_RDBUFF | DAP_TRANSFER_RNW, 0) {
+                    Ok(data) => response.push_u32(data)?,
+                    Err(err) => response_value = transfer_status(err),
+                }
+            } else if state.check_write || self.port == DAP_PORT_JTAG {
+                // 最後のライトの結果を確認する
+                if let Err(err) = self.transfer_with_retry(DP_RDBUFF | DAP_TRANSFER_RNW, 0) {
+                    response_value = transfer_status(err);
+                }
+            }
+        }
+
+        response.set(header_offset + 1, &[response_count as u8, response_value])?;
+        Ok(request_length)
+    }
+
+    /// DAP_WriteABORT (0x08)
+    fn dap_write_abort(&mut self, request: &[u8], response: &mut ResponseWriter) -> Result<usize, DapError> {
+        if request.len() < 6 {
+            return Err(DapError::InvalidCommand);
+        }
+        let value = read_u32(&request[2..]);
+        let port = self.port;
+        let result = match port {
+            DAP_PORT_SWD => self.swdio.swd_transfer(&self.config, DP_ABORT, value),
+            // request[1]はDAP Index。JTAGのみ使う
+            DAP_PORT_JTAG if !self.select_jtag_device(request[1]) => Err(DapError::InvalidCommand),
+            DAP_PORT_JTAG => {
+                let jtagio = self.swdio.jtag().ok_or(DapError::InternalError)?;
+                if self.jtag_ir != JTAG_ABORT {
+                    jtag_ir(jtagio, &self.config, &self.jtag, JTAG_ABORT);
+                    self.jtag_ir = JTAG_ABORT;
+                }
+                // ABORTスキャンチェーンはDPACCと同じ形式で、RnW = 0, A[3:2] = 0
+                jtag_transfer(jtagio, &self.config, &self.jtag, 0, value)
+            }
+            _ => Err(DapError::InvalidCommand),
+        };
+        let status = match result {
+            Ok(_) => DAP_OK,
+            Err(_) => DAP_ERROR,
+        };
+        response.extend(&[0x08, status])?;
+        Ok(6)
+    }
+
+    /// DAP_Delay (0x09)
+    fn dap_delay(&mut self, request: &[u8], response: &mut ResponseWriter) -> Result<usize, DapError> {
+        if request.len() < 3 {
+            return Err(DapError::InvalidCommand);
+        }
+        let delay_us = u16::from_le_bytes([request[1], request[2]]) as u32;
+        self.swdio.delay_us(delay_us);
+        response.extend(&[0x09, DAP_OK])?;
+        Ok(3)
+    }
+
+    /// DAP_ResetTarget (0x0A)
+    fn dap_reset_target(&mut self, _request: &[u8], response: &mut ResponseWriter) -> Result<usize, DapError> {
+        let (status, execute) = match self.reset_target {
+            Some(reset_target) => {
+                let status = match reset_target(&mut self.swdio, &self.config) {
+                    Ok(_) => DAP_OK,
+                    Err(_) => DAP_ERROR,
+                };
+                // リセットシーケンスを実行した
+                (status, 1)
+            }
+            // リセットシーケンスは実装されていない
+            None => (DAP_OK, 0),
+        };
+        response.extend(&[0x0a, status, execute])?;
+        Ok(1)
+    }
+
+    /// DAP_SWJ_Pins (0x10)
+    ///
+    /// The pin wait is approximate. It counts the calls of `SwdIo::delay_us(1)`,
+    /// so the time to read the pins in each iteration makes the actual wait longer.
+    fn dap_swj_pins(&mut self, request: &[u8], response: &mut ResponseWriter) -> Result<usize, DapError> {
+        if request.len() < 7 {
+            return Err(DapError::InvalidCommand);
+        }
+        let output = request[1];
+        let select = request[2];
+        // 待ち時間は最大3秒
+        let wait_us = read_u32(&request[3..]).min(SWJ_PINS_MAX_WAIT_US);
+        self.swdio.set_swj_pins(output, select);
+        // 選択したピンが出力した値になるか、待ち時間が経過するまで待つ
+        // 経過時間はdelay_us(1)の回数で数えるので、ピンの読み出しにかかる時間は含まない
+        let mut input = self.swdio.get_swj_pins();
+        let mut elapsed_us = 0;
+        while elapsed_us < wait_us && (input ^ output) & select != 0 {
+            self.swdio.delay_us(1);
+            elapsed_us += 1;
+            input = self.swdio.get_swj_pins();
+        }
+        response.extend(&[0x10, input])?;
+        Ok(7)
+    }
+
+    /// DAP_SWJ_Clock (0x11)
+    fn dap_swj_clock(&mut self, request: &[u8], response: &mut ResponseWriter) -> Result<usize, DapError> {
+        if request.len() < 5 {
+            return Err(DapError::InvalidCommand);
+        }
+        let frequency_hz = read_u32(&request[1..]);
+        let status = match self.swdio.swj_clock(&mut self.config, frequency_hz) {
+            Ok(()) => DAP_OK,
+            Err(_) => DAP_ERROR,    // 設定できない周波数
+        };
+        response.extend(&[0x11, status])?;
+        Ok(5)
+    }
+
+    /// DAP_SWJ_Sequence (0x12)
+    fn dap_swj_sequence(&mut self, request: &[u8], response: &mut ResponseWriter) -> Result<usize, DapError> {
+        if request.len() < 2 {
+            return Err(DapError::InvalidCommand);
+        }
+        // ビット数 0は256ビットを表す
+        let count = match request[1] {
+            0 => 256,
+            count => count as usize,
+        };
+        let request_length = 2 + count.div_ceil(8);
+        if request.len() < request_length {
+            return Err(DapError::InvalidCommand);
+        }
+        self.swdio.swj_sequence(&self.config, count, &request[2..request_length]);
+        response.extend(&[0x12, DAP_OK])?;
+        Ok(request_length)
+    }
+
+    /// DAP_SWD_Configure (0x13)
+    fn dap_swd_configure(&mut self, request: &[u8], response: &mut ResponseWriter) -> Result<usize, DapError> {
+        if request.len() < 2 {
+            return Err(DapError::InvalidCommand);
+        }
+        // bit 1:0 ターンアラウンド期間 - 1, bit 2 常にデータフェーズを生成する
+        self.config.turn_around_cycles = (request[1] & 0x03) as u32 + 1;
+        self.config.always_generate_data_phase = request[1] & 0x04 != 0;
+        response.extend(&[0x13, DAP_OK])?;
+        Ok(2)
+    }
+
+    /// DAP_SWO_Transport (0x17)
+    fn dap_swo_transport(&mut self, request: &[u8], response: &mut ResponseWriter) -> Result<usize, DapError> {
+        if request.len() < 2 {
+            return Err(DapError::InvalidCommand);
+        }
+        let status = if self.swo.set_transport(request[1]) { DAP_OK } else { DAP_ERROR };
+        response.extend(&[0x17, status])?;
+        Ok(2)
+    }
+
+    /// DAP_SWO_Mode (0x18)
+    fn dap_swo_mode(&mut self, request: &[u8], response: &mut ResponseWriter) -> Result<usize, DapError> {
+        if request.len() < 2 {
+            return Err(DapError::InvalidCommand);
+        }
+        let status = if self.swo.set_mode(request[1]) { DAP_OK } else { DAP_ERROR };
+        response.extend(&[0x18, status])?;
+        Ok(2)
+    }
+
+    /// DAP_SWO_Baudrate (0x19)
+    fn dap_swo_baudrate(&mut self, request: &[u8], response: &mut ResponseWriter) -> Result<usize, DapError> {
+        if request.len() < 5 {
+            return Err(DapError::InvalidCommand);
+        }
+        let baudrate = read_u32(&request[1..]);
+        // 実際に設定したボーレートを返す。設定できなければ0
+        let baudrate = self.swo.set_baudrate(baudrate);
+        response.push(0x19)?;
+        response.push_u32(baudrate)?;
+        Ok(5)
+    }
+
+    /// DAP_SWO_Control (0x1A)
+    fn dap_swo_control(&mut self, request: &[u8], response: &mut ResponseWriter) -> Result<usize, DapError> {
+        if request.len() < 2 {
+            return Err(DapError::InvalidCommand);
+        }
+        let status = if self.swo.control(request[1] & 0x01 != 0) { DAP_OK } else { DAP_ERROR };
+        response.extend(&[0x1a, status])?;
+        Ok(2)
+    }
+
+    /// DAP_SWO_Status (0x1B)
+    fn dap_swo_status(&mut self, _request: &[u8], response: &mut ResponseWriter) -> Result<usize, DapError> {
+        self.swo.poll();
+        response.extend(&[0x1b, self.swo.take_status()])?;
+        response.push_u32(self.swo.len() as u32)?;
+        Ok(1)
+    }
+
+    /// DAP_SWO_Data (0x1C)
+    fn dap_swo_data(&mut self, request: &[u8], response: &mut ResponseWriter) -> Result<usize, DapError> {
+        if request.len() < 3 {
+            return Err(DapError::InvalidCommand);
+        }
+        let max_count = u16::from_le_bytes([request[1], request[2]]) as usize;
+        self.swo.poll();
+        response.extend(&[0x1c, self.swo.take_status()])?;
+        // DAP_SWO_Dataで転送する設定の場合のみデータを返す。レスポンスに収まる分だけ返す
+        let count = if self.swo.transport() == SWO_TRANSPORT_DATA {
+            max_count.min(self.swo.len()).min(response.remaining().saturating_sub(2))
+        } else {
+            0
+        };
+        response.extend(&(count as u16).to_le_bytes())?;
+        let data = response.reserve(count)?;
+        self.swo.read(data);
+        Ok(3)
+    }
+
+    /// DAP_SWO_ExtendedStatus (0x1E)
+    fn dap_swo_extended_status(&mut self, request: &[u8], response: &mut ResponseWriter) -> Result<usize, DapError> {
+        if request.len() < 2 {
+            return Err(DapError::InvalidCommand);
+        }
+        let control = request[1];
+        self.swo.poll();
+        response.push(0x1e)?;
+        if control & 0x01 != 0 {
+            // Trace Status
+            response.push(self.swo.take_status())?;
+        }
+        if control & 0x02 != 0 {
+            // Trace Count
+            response.push_u32(self.swo.len() as u32)?;
+        }
+        if control & 0x04 != 0 {
+            // Index and TD_TimeStamp。テスト・ドメイン・タイマーはないのでタイムスタンプは0
+            response.push_u32(self.swo.index())?;
+            response.push_u32(0)?;
+        }
+        Ok(2)
+    }
+
+    /// DAP_UART_Transport (0x1F)
+    fn dap_uart_transport(&mut self, request: &[u8], response: &mut ResponseWriter) -> Result<usize, DapError> {
+        if request.len() < 2 {
+            return Err(DapError::InvalidCommand);
+        }
+        let status = if self.uart.set_transport(request[1]) { DAP_OK } else { DAP_ERROR };
+        response.extend(&[0x1f, status])?;
+        Ok(2)
+    }
+
+    /// DAP_UART_Configure (0x20)
+    fn dap_uart_configure(&mut self, request: &[u8], response: &mut ResponseWriter) -> Result<usize, DapError> {
+        if request.len() < 6 {
+            return Err(DapError::InvalidCommand);
+        }
+        let baudrate = read_u32(&request[2..]);
+        // 設定できなかった項目のビットと、実際に設定したボーレートを返す。設定できなければボーレートは0
+        let (status, baudrate) = match UartLineConfig::from_control(request[1], baudrate)
+            .and_then(|config| self.uart.configure(&config))
+        {
+            Ok(baudrate) => (0, baudrate),
+            Err(errors) => (errors, 0),
+        };
+        response.extend(&[0x20, status])?;
+        response.push_u32(baudrate)?;
+        Ok(6)
+    }
+
+    /// DAP_UART_Control (0x21)
+    fn dap_uart_control(&mut self, request: &[u8], response: &mut ResponseWriter) -> Result<usize, DapError> {
+        if request.len() < 2 {
+            return Err(DapError::InvalidCommand);
+        }
+        self.uart.control(request[1]);
+        response.extend(&[0x21, DAP_OK])?;
+        Ok(2)
+    }
+
+    /// DAP_UART_Status (0x22)
+    fn dap_uart_status(&mut self, _request: &[u8], response: &mut ResponseWriter) -> Result<usize, DapError> {
+        self.uart.poll();
+        response.extend(&[0x22, self.uart.take_status()])?;
+        response.push_u32(self.uart.rx_len() as u32)?;
+        response.push_u32(self.uart.tx_len() as u32)?;
+        Ok(1)
+    }
+
+    /// DAP_UART_Transfer (0x23)
+    fn dap_uart_transfer(&mut self, request: &[u8], response: &mut ResponseWriter) -> Result<usize, DapError> {
+        if request.len() < 3 {
+            return Err(DapError::InvalidCommand);
+        }
+        let request_count = u16::from_le_bytes([request[1], request[2]]) as usize;
+        if request.len() < 3 + request_count {
+            return Err(DapError::InvalidCommand);
+        }
+        // DAP_UART_Transferで転送する設定の場合のみ送受信する
+        let enabled = self.uart.transport() == UART_TRANSPORT_DAP_COMMAND;
+        // 送信バッファに入る分だけ受け付け、受け付けたバイト数を返す
+        let tx_count = if enabled { self.uart.write(&request[3..3 + request_count]) } else { 0 };
+        self.uart.poll();
+        // 受信したデータはレスポンスに収まる分だけ返す
+        // コマンド, ステータス, 送信バイト数 (2バイト), 受信バイト数 (2バイト) の6バイトの後に続ける
+        let rx_count = if enabled {
+            self.uart.rx_len().min(response.remaining().saturating_sub(6))
+        } else {
+            0
+        };
+        response.extend(&[0x23, self.uart.take_status()])?;
+        response.extend(&(tx_count as u16).to_le_bytes())?;
+        response.extend(&(rx_count as u16).to_le_bytes())?;
+        let data = response.reserve(rx_count)?;
+        self.uart.read(data);
+        Ok(3 + request_count)
+    }
+
+    /// DAP_SWD_Sequence (0x1D)
+    fn dap_swd_sequence(&mut self, request: &[u8], response: &mut ResponseWriter) -> Result<usize, DapError> {
+        if request.len() < 2 {
+            return Err(DapError::InvalidCommand);
+        }
+        let sequence_count = request[1] as usize;
+        // 先にリクエストとレスポンスの長さを確認しておく
+        let mut request_length = 2;
+        let mut response_length = 2;
+        for _ in 0..sequence_count {
+            let info = *request.get(request_length).ok_or(DapError::InvalidCommand)?;
+            let bytes = swd_sequence_bit_count(info).div_ceil(8);
+            request_length += 1;
+            if info & SWD_SEQUENCE_DIN != 0 {
+                response_length += bytes;
+            } else {
+                request_length += bytes;
+            }
+        }
+        if request.len() < request_length {
+            return Err(DapError::InvalidCommand);
+        }
+        if response.remaining() < response_length {
+            return Err(DapError::InternalError);
+        }
+
+        response.extend(&[0x1d, DAP_OK])?;
+        let mut request_offset = 2;
+        for _ in 0..sequence_count {
+            let info = request[request_offset];
+            let count = swd_sequence_bit_count(info);
+            let bytes = count.div_ceil(8);
+            request_offset += 1;
+            if info & SWD_SEQUENCE_DIN != 0 {
+                // 入力シーケンス。読み出したビットはLSBから順に詰める
+                let data = response.reserve(bytes)?;
+                self.swdio.swd_read_sequence(&self.config, count, data);
+            } else {
+                // 出力シーケンス
+                self.swdio.swd_write_sequence(&self.config, count, &request[request_offset..request_offset + bytes]);
+                request_offset += bytes;
+            }
+        }
+        Ok(request_length)
+    }
+
+    /// DAP_JTAG_Sequence (0x14)
+    fn dap_jtag_sequence(&mut self, request: &[u8], response: &mut ResponseWriter) -> Result<usize, DapError> {
+        if request.len() < 2 {
+            return Err(DapError::InvalidCommand);
+        }
+        let sequence_count = request[1] as usize;
+        // 先にリクエストとレスポンスの長さを確認しておく
+        let mut request_length = 2;
+        let mut response_length = 2;
+        for _ in 0..sequence_count {
+            let info = *request.get(request_length).ok_or(DapError::InvalidCommand)?;
+            let bytes = jtag_sequence_bit_count(info).div_ceil(8);
+            request_length += 1 + bytes;
+            if info & JTAG_SEQUENCE_TDO != 0 {
+                response_length += bytes;
+            }
+        }
+        if request.len() < request_length {
+            return Err(DapError::InvalidCommand);
+        }
+        if response.remaining() < response_length {
+            return Err(DapError::InternalError);
+        }
+
+        let jtagio = match self.swdio.jtag() {
+            Some(jtagio) => jtagio,
+            None => {
+                response.extend(&[0x14, DAP_ERROR])?;
+                return Ok(request_length);
+            }
+        };
+        response.extend(&[0x14, DAP_OK])?;
+        let mut request_offset = 2;
+        for _ in 0..sequence_count {
+            let info = request[request_offset];
+            let count = jtag_sequence_bit_count(info);
+            let bytes = count.div_ceil(8);
+            request_offset += 1;
+            let tdi = request[request_offset..request_offset + bytes]
+                .iter()
+                .enumerate()
+                .fold(0u64, |tdi, (index, byte)| tdi | (*byte as u64) << (index * 8));
+            request_offset += bytes;
+            let tdo = jtagio.jtag_sequence(&self.config, count, info & JTAG_SEQUENCE_TMS != 0, tdi);
+            if info & JTAG_SEQUENCE_TDO != 0 {
+                // TDOの値はLSBから順に詰める
+                response.extend(&tdo.to_le_bytes()[..bytes])?;
+            }
+        }
+        Ok(request_length)
+    }
+
+    /// DAP_JTAG_Configure (0x15)
+    fn dap_jtag_configure(&mut self, request: &[u8], response: &mut ResponseWriter) -> Result<usize, DapError> {
+        if request.len() < 2 {
+            return Err(DapError::InvalidCommand);
+        }
+        let count = request[1] as usize;
+        let request_length = 2 + count;
+        if request.len() < request_length {
+            return Err(DapError::InvalidCommand);
+        }
+        let status = match self.jtag.configure(&request[2..request_length]) {
+            Ok(()) => DAP_OK,
+            Err(_) => DAP_ERROR,    // デバイス数が多すぎる
+        };
+        response.extend(&[0x15, status])?;
+        Ok(request_length)
+    }
+
+    /// DAP_JTAG_IDCODE (0x16)
+    fn dap_jtag_idcode(&mut self, request: &[u8], response: &mut ResponseWriter) -> Result<usize, DapError> {
+        if request.len() < 2 {
+            return Err(DapError::InvalidCommand);
+        }
+        let mut status = DAP_ERROR;
+        let mut idcode = 0;
+        if self.port == DAP_PORT_JTAG && self.select_jtag_device(request[1]) {
+            if let Some(jtagio) = self.swdio.jtag() {
+                jtag_ir(jtagio, &self.config, &self.jtag, JTAG_IDCODE);
+                self.jtag_ir = JTAG_IDCODE;
+                idcode = jtag_read_idcode(jtagio, &self.config, &self.jtag);
+                status = DAP_OK;
+            }
+        }
+        response.extend(&[0x16, status])?;
+        response.push_u32(idcode)?;
+        Ok(2)
+    }
+
+    /// Executes one transfer in DAP_Transfer, handling the posted read pipeline.
+    ///
+    /// On SWD only AP reads are posted, while all reads are posted on JTAG.
+    fn transfer_one(
+        &mut self,
+        transfer_request: u8,
+        value: u32,
+        state: &mut TransferState,
+        response: &mut ResponseWriter,
+    ) -> Result<(), DapError> {
+        let is_ap = transfer_request & DAP_TRANSFER_APNDP != 0;
+        if transfer_request & DAP_TRANSFER_RNW != 0 {
+            // リード
+            let is_posted = is_ap || self.port == DAP_PORT_JTAG;
+            if state.post_read {
+                let data = if transfer_request & DAP_TRANSFER_MATCH_VALUE == 0
+                    && is_posted
+                    && state.post_read_ap == is_ap
+                {
+                    // 前回のリードの結果を読み出しつつ、次のリードを発行
+                    self.transfer_with_retry(transfer_request, 0)?
+                } else {
+                    // 前回のリードの結果をRDBUFFから読み出す
+                    let data = self.transfer_with_retry(DP_RDBUFF | DAP_TRANSFER_RNW, 0)?;
+                    state.post_read = false;
+                    data
+                };
+                response.push_u32(data)?;
+            }
+            if transfer_request & DAP_TRANSFER_MATCH_VALUE != 0 {
+                // 読み出した値がマスク付きで一致するまで読み続ける
+                if is_posted {
+                    // リードを発行
+                    self.transfer_with_retry(transfer_request, 0)?;
+                }
+                let mut match_retry = 0;
+                loop {
+                    let data = self.transfer_with_retry(transfer_request, 0)?;
+                    if data & self.match_mask == value {
+                        break;
+                    }
+                    if match_retry >= self.config.match_retry {
+                        return Err(DapError::ExceedRetryCount);
+                    }
+                    match_retry += 1;
+                }
+            } else if !state.post_read {
+                if is_posted {
+                    // リードを発行。結果は次の転送で読み出す
+                    self.transfer_with_retry(transfer_request, 0)?;
+                    state.post_read = true;
+                    state.post_read_ap = is_ap;
+                } else {
+                    // DPリード
+                    let data = self.transfer_with_retry(transfer_request, 0)?;
+                    response.push_u32(data)?;
+                }
+            }
+            state.check_write = false;
+        } else {
+            // ライト
+            if state.post_read {
+                // 前回のリードの結果をRDBUFFから読み出す
+                let data = self.transfer_with_retry(DP_RDBUFF | DAP_TRANSFER_RNW, 0)?;
+                response.push_u32(data)?;
+                state.post_read = false;
+            }
+            if transfer_request & DAP_TRANSFER_MATCH_MASK != 0 {
+                // 一致待ちのマスク値を更新
+                self.match_mask = value;
+            } else {
+                self.transfer_with_retry(transfer_request, value)?;
+                state.check_write = true;
+            }
+        }
+        Ok(())
+    }
+
+    /// DAP_TransferBlock (0x06)
+    fn dap_transfer_block(
+        &mut self,
+        request: &[u8],
+        response: &mut ResponseWriter,
+        abort: &mut dyn FnMut() -> bool,
+    ) -> Result<usize, DapError> {
+        if request.len() < 5 {
+            return Err(DapError::InvalidCommand);
+        }
+        let transfer_count = u16::from_le_bytes([request[2], request[3]]) as usize;
+        let transfer_request = request[4] & (DAP_TRANSFER_APNDP | DAP_TRANSFER_RNW | DAP_TRANSFER_A2 | DAP_TRANSFER_A3);
+        let is_read = transfer_request & DAP_TRANSFER_RNW != 0;
+        let request_length = if is_read { 5 } else { 5 + transfer_count * 4 };
+        if request.len() < request_length {
+            return Err(DapError::InvalidCommand);
+        }
+        let header_offset = response.len();
+        response.extend(&[0x06, 0, 0, 0])?;
+        // request[1]はDAP Index。JTAGのみ使う
+        if !self.select_jtag_device(request[1]) {
+            return Ok(request_length);
+        }
+
+        // 読み出しはレスポンス・バッファに収まる分だけ行う
+        let transfer_count = if is_read { transfer_count.min(response.remaining() / 4) } else { transfer_count };
+        let mut response_count = 0;
+        let result = if is_read {
+            self.transfer_block_read(transfer_request, transfer_count, response, &mut response_count, abort)
+        } else {
+            self.transfer_block_write(transfer_request, &request[5..request_length], &mut response_count, abort)
+        };
+        let response_value = match result {
+            Ok(()) if transfer_count == 0 => 0,
+            Ok(()) => DAP_TRANSFER_OK,
+            Err(err) => transfer_status(err),
+        };
+
+        let response_count = (response_count as u16).to_le_bytes();
+        response.set(header_offset + 1, &[response_count[0], response_count[1], response_value])?;
+        Ok(request_length)
+    }
+
+    fn transfer_block_read(
+        &mut self,
+        transfer_request: u8,
+        transfer_count: usize,
+        response: &mut ResponseWriter,
+        response_count: &mut usize,
+        abort: &mut dyn FnMut() -> bool,
+    ) -> Result<(), DapError> {
+        if transfer_count == 0 {
+            return Ok(());
+        }
+        let is_posted = transfer_request & DAP_TRANSFER_APNDP != 0 || self.port == DAP_PORT_JTAG;
+        if is_posted {
+            // リードを発行。結果は次の転送で読み出す
+            self.transfer_with_retry(transfer_request, 0)?;
+        }
+        for index in 0..transfer_count {
+            // 中止要求があっても、発行済みのリードの結果は読み出す
+            let is_aborted = abort();
+            if is_aborted && !is_posted {
+                break;
+            }
+            let is_last = is_aborted || index == transfer_count - 1;
+            let request = if is_posted && is_last {
+                // 最後のリードの結果はRDBUFFから読み出す
+                DP_RDBUFF | DAP_TRANSFER_RNW
+            } else {
+                transfer_request
+            };
+            let data = self.transfer_with_retry(request, 0)?;
+            response.push_u32(data)?;
+            *response_count += 1;
+            if is_last {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn transfer_block_write(
+        &mut self,
+        transfer_request: u8,
+        data: &[u8],
+        response_count: &mut usize,
+        abort: &mut dyn FnMut() -> bool,
+    ) -> Result<(), DapError> {
+        if data.is_empty() {
+            return Ok(());
+        }
+        for chunk in data.chunks_exact(4) {
+            if abort() {
+                // 残りの転送を中止する
+                break;
+            }
+            self.transfer_with_retry(transfer_request, read_u32(chunk))?;
+            *response_count += 1;
+        }
+        // 最後のライトの結果を確認する
+        self.transfer_with_retry(DP_RDBUFF | DAP_TRANSFER_RNW, 0)?;
+        Ok(())
+    }
+
+    /// Selects the device in the JTAG scan chain used by the following transfers.
+    ///
+    /// Returns false if the device does not exist. Always succeeds on SWD.
+    fn select_jtag_device(&mut self, index: u8) -> bool {
+        if self.port != DAP_PORT_JTAG {
+            return true;
+        }
+        if index as usize >= self.jtag.count {
+            return false;
+        }
+        self.jtag.index = index as usize;
+        // 最初の転送で必ずIRを設定させる
+        self.jtag_ir = 0;
+        true
+    }
+
+    /// Performs one SWD/JTAG transfer on the connected port.
+    fn transfer(&mut self, request: SwdRequest, data: u32) -> Result<u32, DapError> {
+        if self.port != DAP_PORT_JTAG {
+            return self.swdio.swd_transfer(&self.config, request, data);
+        }
+        let jtagio = self.swdio.jtag().ok_or(DapError::InternalError)?;
+        // DPACCかAPACCを選択する
+        let ir = if request & DAP_TRANSFER_APNDP != 0 { JTAG_APACC } else { JTAG_DPACC };
+        if self.jtag_ir != ir {
+            jtag_ir(jtagio, &self.config, &self.jtag, ir);
+            self.jtag_ir = ir;
+        }
+        jtag_transfer(jtagio, &self.config, &self.jtag, request, data)
+    }
+
+    /// Performs one transfer, retrying while the target responds with WAIT.
+    fn transfer_with_retry(&mut self, request: SwdRequest, data: u32) -> Result<u32, DapError> {
+        let wait_retry = self.config.wait_retry;
+        retry_on_wait(wait_retry, || self.transfer(request, data))
+    }
+}
+
+/// Writes a DAP_Info string with its length. The string is terminated by NUL unless it is empty.
+fn push_info_string(response: &mut ResponseWriter, value: &str) -> Result<(), DapError> {
+    if value.is_empty() {
+        return response.push(0);
+    }
+    let bytes = value.as_bytes();
+    // 長さはNULを含めて1バイトに収める
+    let length = bytes.len().min(u8::MAX as usize - 1);
+    response.push(length as u8 + 1)?;
+    response.extend(&bytes[..length])?;
+    response.push(0)
+}
+
+/// Writes a 32bit DAP_Info value with its length. Zero is reported as not available.
+fn push_info_u32(response: &mut ResponseWriter, value: u32) -> Result<(), DapError> {
+    if value == 0 {
+        return response.push(0);
+    }
+    response.push(4)?;
+    response.push_u32(value)
+}
+
+/// Returns the length of a DAP_Transfer request including all transfers.
+fn transfer_request_length(request: &[u8]) -> Result<usize, DapError> {
+    if request.len() < 3 {
+        return Err(DapError::InvalidCommand);
+    }
+    let transfer_count = request[2] as usize;
+    let mut offset = 3;
+    for _ in 0..transfer_count {
+        let transfer_request = *request.get(offset).ok_or(DapError::InvalidCommand)?;
+        offset += 1;
+        if transfer_request & DAP_TRANSFER_RNW == 0 || transfer_request & DAP_TRANSFER_MATCH_VALUE != 0 {
+            offset += 4;
+        }
+    }
+    if offset > request.len() {
+        return Err(DapError::InvalidCommand);
+    }
+    Ok(offset)
+}
+
+/// Returns the number of bits of a DAP_SWD_Sequence from its sequence info byte.
+fn swd_sequence_bit_count(info: u8) -> usize {
+    // ビット数 0は64ビットを表す
+    match (info & SWD_SEQUENCE_CLK) as usize {
+        0 => 64,
+        count => count,
+    }
+}
+
+/// Returns the number of TCK cycles of a DAP_JTAG_Sequence from its sequence info byte.
+fn jtag_sequence_bit_count(info: u8) -> usize {
+    // ビット数 0は64ビットを表す
+    match (info & JTAG_SEQUENCE_TCK) as usize {
+        0 => 64,
+        count => count,
+    }
+}
+
+/// Converts the error of a transfer into the response value of DAP_Transfer.
+fn transfer_status(error: DapError) -> u8 {
+    match error {
+        DapError::SwdError(ack) => ack,
+        DapError::ExceedRetryCount => DAP_TRANSFER_OK | DAP_TRANSFER_MISMATCH,
+        _ => DAP_TRANSFER_ERROR,
+    }
+}
+
+fn read_u32(buffer: &[u8]) -> u32 {
+    u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim_target::SimulatedTarget;
+
+    fn process(processor: &mut DapProcessor<SimulatedTarget<256>>, request: &[u8]) -> Vec<u8> {
+        let mut response = [0u8; DAP_PACKET_SIZE as usize];
+        let length = processor.process(request, &mut response);
+        response[..length].to_vec()
+    }
+
+    #[test]
+    fn info_reports_packet_size_and_count() {
+        let mut processor = DapProcessor::new(SimulatedTarget::new(0x2000_0000)).with_packet_count(4);
+        assert_eq!(process(&mut processor, &[0x00, 0xff]), [0x00, 2, 64, 0]);
+        assert_eq!(process(&mut processor, &[0x00, 0xfe]), [0x00, 1, 4]);
+        assert_eq!(process(&mut processor, &[0x00, 0x04]), b"\x00\x062.1.0\x00");
+    }
+
+    #[test]
+    fn connect_and_disconnect_swd() {
+        let mut processor = DapProcessor::new(SimulatedTarget::<256>::new(0x2000_0000));
+        assert_eq!(process(&mut processor, &[0x02, 0x00]), [0x02, DAP_PORT_SWD]);
+        assert!(processor.is_connected());
+        assert!(processor.swdio().is_connected());
+        assert_eq!(process(&mut processor, &[0x03]), [0x03, DAP_OK]);
+        assert!(!processor.is_connected());
+    }
+
+    #[test]
+    fn queued_commands_are_answered_as_execute_commands() {
+        let mut processor = DapProcessor::new(SimulatedTarget::<256>::new(0x2000_0000));
+        let response = process(&mut processor, &[0x7e, 2, 0x00, 0xfe, 0x02, 0x01]);
+        assert_eq!(response, [0x7f, 2, 0x00, 1, 1, 0x02, DAP_PORT_SWD]);
+    }
+
+    #[test]
+    fn transfer_abort_in_execute_commands_is_answered() {
+        let mut processor = DapProcessor::new(SimulatedTarget::<256>::new(0x2000_0000));
+        let response = process(&mut processor, &[0x7f, 3, 0x02, 0x01, 0x07, 0x03]);
+        assert_eq!(response, [0x7f, 3, 0x02, DAP_PORT_SWD, 0x07, 0x03, DAP_OK]);
+    }
+
+    #[test]
+    fn padding_after_command_is_ignored() {
+        let mut processor = DapProcessor::new(SimulatedTarget::<256>::new(0x2000_0000));
+        let mut request = [0u8; DAP_PACKET_SIZE as usize];
+        request[..2].copy_from_slice(&[0x00, 0xfe]);
+        let mut response = [0u8; DAP_PACKET_SIZE as usize];
+        let length = processor.process_command_with_abort(&request, &mut response, &mut || false);
+        assert_eq!(response[..length], [0x00, 1, 1]);
+
+        request[..6].copy_from_slice(&[0x7f, 2, 0x00, 0xfe, 0x02, 0x01]);
+        let length = processor.process_command_with_abort(&request, &mut response, &mut || false);
+        assert_eq!(response[..length], [0x7f, 2, 0x00, 1, 1, 0x02, DAP_PORT_SWD]);
+    }
+
+    #[test]
+    fn swj_pins_drives_nreset() {
+        let mut processor = DapProcessor::new(SimulatedTarget::<256>::new(0x2000_0000));
+        // nRESETをLowにして、Lowになるまで最大1000us待つ
+        let response = process(&mut processor, &[0x10, 0x00, SWJ_PIN_NRESET, 0xe8, 0x03, 0, 0]);
+        assert_eq!(response, [0x10, SWJ_PIN_SWCLK_TCK | SWJ_PIN_SWDIO_TMS]);
+        assert!(processor.swdio().is_reset_asserted());
+        assert_eq!(processor.swdio().reset_count(), 1);
+
+        let response = process(&mut processor, &[0x10, SWJ_PIN_NRESET, SWJ_PIN_NRESET, 0xe8, 0x03, 0, 0]);
+        assert_eq!(response, [0x10, SWJ_PIN_SWCLK_TCK | SWJ_PIN_SWDIO_TMS | SWJ_PIN_NRESET]);
+        // ピンはすぐに出力した値になるので待たない
+        assert_eq!(processor.swdio().elapsed_us(), 0);
+        assert_eq!(process(&mut processor, &[0x10, 0x00, SWJ_PIN_NRESET, 0, 0, 0]), [DAP_INVALID]);
+    }
+
+    #[test]
+    fn delay_waits_by_delay_us() {
+        let mut processor = DapProcessor::new(SimulatedTarget::<256>::new(0x2000_0000));
+        assert_eq!(process(&mut processor, &[0x09, 0x10, 0x27]), [0x09, DAP_OK]);
+        assert_eq!(processor.swdio().elapsed_us(), 10000);
+        assert_eq!(process(&mut processor, &[0x09, 0x10]), [DAP_INVALID]);
+    }
+
+    /// `SwoReceiver` in UART mode up to 1MBd, which receives `data` while started.
+    #[derive(Default)]
+    struct ScriptedSwo {
+        mode: Option<SwoMode>,
+        started: bool,
+        data: Vec<u8>,
+    }
+
+    impl SwoReceiver for ScriptedSwo {
+        fn set_mode(&mut self, mode: SwoMode) -> bool {
+            self.mode = Some(mode);
+            mode != SwoMode::Manchester
+        }
+
+        fn set_baudrate(&mut self, baudrate: u32) -> u32 {
+            if baudrate <= 1_000_000 {
+                baudrate
+            } else {
+                0
+            }
+        }
+
+        fn start(&mut self) {
+            self.started = true;
+        }
+
+        fn stop(&mut self) {
+            self.started = false;
+        }
+
+        fn read(&mut self) -> nb::Result<u8, ()> {
+            if !self.started || self.data.is_empty() {
+                return Err(nb::Error::WouldBlock);
+            }
+            Ok(self.data.remove(0))
+        }
+    }
+
+    #[test]
+    fn swo_capture() {
+        let mut processor = DapProcessor::new(SimulatedTarget::<256>::new(0x2000_0000))
+            .with_swo(ScriptedSwo::default(), false);
+        let process = |processor: &mut DapProcessor<SimulatedTarget<256>, ScriptedSwo>, request: &[u8]| {
+            let mut response = [0u8; DAP_PACKET_SIZE as usize];
+            let length = processor.process(request, &mut response);
+            response[..length].to_vec()
+        };
+        assert_eq!(process(&mut processor, &[0x17, SWO_TRANSPORT_DATA]), [0x17, DAP_OK]);
+        // ストリーミング用のエンドポイントはない
+        assert_eq!(process(&mut processor, &[0x17, SWO_TRANSPORT_STREAM]), [0x17, DAP_ERROR]);
+        // Manchesterには対応していない
+        assert_eq!(process(&mut processor, &[0x18, 2]), [0x18, DAP_ERROR]);
+        assert_eq!(process(&mut processor, &[0x19, 0x00, 0xc2, 0x01, 0x00]), [0x19, 0, 0, 0, 0]);
+
+        assert_eq!(process(&mut processor, &[0x18, 1]), [0x18, DAP_OK]);
+        // 設定できないボーレートなら受信機を止めて、捕捉を開始できない状態にする
+        assert_eq!(process(&mut processor, &[0x19, 0x80, 0x84, 0x1e, 0x00]), [0x19, 0, 0, 0, 0]);
+        assert_eq!(processor.swo().receiver().mode, Some(SwoMode::Off));
+        assert_eq!(process(&mut processor, &[0x1a, 0x01]), [0x1a, DAP_ERROR]);
+
+        assert_eq!(process(&mut processor, &[0x18, 1]), [0x18, DAP_OK]);
+        assert_eq!(process(&mut processor, &[0x19, 0x00, 0xc2, 0x01, 0x00]), [0x19, 0x00, 0xc2, 0x01, 0x00]);
+        assert_eq!(process(&mut processor, &[0x1a, 0x01]), [0x1a, DAP_OK]);
+        processor.swo().receiver().data.extend(b"abc");
+        // 捕捉中は転送方法を変更できない
+        assert_eq!(process(&mut processor, &[0x17, SWO_TRANSPORT_NONE]), [0x17, DAP_ERROR]);
+
+        assert_eq!(process(&mut processor, &[0x1b]), [0x1b, SWO_STATUS_CAPTURE, 3, 0, 0, 0]);
+        assert_eq!(process(&mut processor, &[0x1c, 2, 0]), [0x1c, SWO_STATUS_CAPTURE, 2, 0, b'a', b'b']);
+        // Trace Status, Trace Count, Index, TD_TimeStamp
+        assert_eq!(
+            process(&mut processor, &[0x1e, 0x07]),
+            [0x1e, SWO_STATUS_CAPTURE, 1, 0, 0, 0, 3, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(process(&mut processor, &[0x1a, 0x00]), [0x1a, DAP_OK]);
+        assert_eq!(process(&mut processor, &[0x1c, 4, 0]), [0x1c, 0, 1, 0, b'c']);
+    }
+
+    /// `UartIo` which receives what it transmits.
+    #[derive(Default)]
+    struct LoopbackUart {
+        data: Vec<u8>,
+    }
+
+    impl UartIo for LoopbackUart {
+        fn configure(&mut self, config: &UartLineConfig) -> Result<u32, u8> {
+            Ok(config.baudrate)
+        }
+
+        fn read(&mut self) -> nb::Result<u8, UartError> {
+            if self.data.is_empty() {
+                return Err(nb::Error::WouldBlock);
+            }
+            Ok(self.data.remove(0))
+        }
+
+        fn write(&mut self, data: u8) -> nb::Result<(), ()> {
+            self.data.push(data);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn uart_transfer() {
+        let mut processor = DapProcessor::new(SimulatedTarget::<256>::new(0x2000_0000))
+            .with_uart(LoopbackUart::default(), false);
+        let mut process = |request: &[u8]| {
+            let mut response = [0u8; DAP_PACKET_SIZE as usize];
+            let length = processor.process(request, &mut response);
+            response[..length].to_vec()
+        };
+        assert_eq!(process(&[0x1f, UART_TRANSPORT_DAP_COMMAND]), [0x1f, DAP_OK]);
+        assert_eq!(
+            process(&[0x21, UART_CONTROL_RX_ENABLE | UART_CONTROL_TX_ENABLE]),
+            [0x21, DAP_OK]
+        );
+        let status = UART_STATUS_RX_ENABLED | UART_STATUS_TX_ENABLED;
+        // Count (2バイト) の後に送信データが続き、Status, TX Count, RX Count (各2バイト) の後に受信データが続く
+        assert_eq!(process(&[0x23, 3, 0, b'a', b'b', b'c']), [0x23, status, 3, 0, 0, 0]);
+        assert_eq!(process(&[0x23, 0, 0]), [0x23, status, 0, 0, 3, 0, b'a', b'b', b'c']);
+
+        // 受信データはレスポンスに収まる分だけ返す
+        let mut request = vec![0x23, 60, 0];
+        request.extend(0..60);
+        assert_eq!(process(&request)[..6], [0x23, status, 60, 0, 0, 0]);
+        let response = process(&[0x23, 0, 0]);
+        assert_eq!(response[..6], [0x23, status, 0, 0, 58, 0]);
+        assert_eq!(response[6..], (0..58).collect::<Vec<u8>>()[..]);
+        assert_eq!(process(&[0x23, 0, 0]), [0x23, status, 0, 0, 2, 0, 58, 59]);
+        // 送信データが足りないリクエストは不正
+        assert_eq!(process(&[0x23, 4, 0, 1, 2, 3]), [DAP_INVALID]);
+    }
+
+    #[test]
+    fn unknown_command_is_invalid() {
+        let mut processor = DapProcessor::new(SimulatedTarget::<256>::new(0x2000_0000));
+        assert_eq!(process(&mut processor, &[0x55]), [DAP_INVALID]);
+    }
+}
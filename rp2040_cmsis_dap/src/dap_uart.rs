@@ -0,0 +1,336 @@
+// Copyright 2023 Kenta Ida
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use heapless::Deque;
+
+/// Size of the buffer which holds the data received from the target.
+pub const UART_RX_BUFFER_SIZE: usize = 1024;
+/// Size of the buffer which holds the data to transmit to the target.
+pub const UART_TX_BUFFER_SIZE: usize = 1024;
+
+// DAP_UART_Transport transports
+pub const UART_TRANSPORT_NONE: u8 = 0;
+pub const UART_TRANSPORT_USB_COM_PORT: u8 = 1;
+pub const UART_TRANSPORT_DAP_COMMAND: u8 = 2;
+
+// DAP_UART_Configure control fields
+const UART_CONTROL_DATA_BITS: u8 = 0x0f;
+const UART_CONTROL_PARITY_SHIFT: u8 = 4;
+const UART_CONTROL_PARITY: u8 = 0x03 << UART_CONTROL_PARITY_SHIFT;
+const UART_CONTROL_STOP_BITS_SHIFT: u8 = 6;
+const UART_CONTROL_STOP_BITS: u8 = 0x03 << UART_CONTROL_STOP_BITS_SHIFT;
+
+// DAP_UART_Configure status bits
+pub const UART_CONFIG_ERROR_DATA_BITS: u8 = 1 << 0;
+pub const UART_CONFIG_ERROR_PARITY: u8 = 1 << 1;
+pub const UART_CONFIG_ERROR_STOP_BITS: u8 = 1 << 2;
+
+// DAP_UART_Control control bits
+pub const UART_CONTROL_RX_ENABLE: u8 = 1 << 0;
+pub const UART_CONTROL_RX_DISABLE: u8 = 1 << 1;
+pub const UART_CONTROL_RX_FLUSH: u8 = 1 << 2;
+pub const UART_CONTROL_TX_ENABLE: u8 = 1 << 4;
+pub const UART_CONTROL_TX_DISABLE: u8 = 1 << 5;
+pub const UART_CONTROL_TX_FLUSH: u8 = 1 << 6;
+
+// DAP_UART_Status status bits
+pub const UART_STATUS_RX_ENABLED: u8 = 1 << 0;
+pub const UART_STATUS_RX_DATA_LOST: u8 = 1 << 1;
+pub const UART_STATUS_FRAMING_ERROR: u8 = 1 << 2;
+pub const UART_STATUS_PARITY_ERROR: u8 = 1 << 3;
+pub const UART_STATUS_TX_ENABLED: u8 = 1 << 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UartParity {
+    None,
+    Odd,
+    Even,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UartStopBits {
+    One,
+    OnePointFive,
+    Two,
+}
+
+/// Line settings given by DAP_UART_Configure.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct UartLineConfig {
+    pub baudrate: u32,
+    /// 5 to 8
+    pub data_bits: u8,
+    pub parity: UartParity,
+    pub stop_bits: UartStopBits,
+}
+
+impl UartLineConfig {
+    /// Decodes the control byte of DAP_UART_Configure.
+    /// Returns the `UART_CONFIG_ERROR_*` bits of the fields which have unknown values on failure.
+    pub fn from_control(control: u8, baudrate: u32) -> Result<Self, u8> {
+        let mut errors = 0;
+        let data_bits = control & UART_CONTROL_DATA_BITS;
+        if !(5..=8).contains(&data_bits) {
+            errors |= UART_CONFIG_ERROR_DATA_BITS;
+        }
+        let parity = match (control & UART_CONTROL_PARITY) >> UART_CONTROL_PARITY_SHIFT {
+            0 => UartParity::None,
+            1 => UartParity::Odd,
+            2 => UartParity::Even,
+            _ => {
+                errors |= UART_CONFIG_ERROR_PARITY;
+                UartParity::None
+            }
+        };
+        let stop_bits = match (control & UART_CONTROL_STOP_BITS) >> UART_CONTROL_STOP_BITS_SHIFT {
+            0 => UartStopBits::One,
+            1 => UartStopBits::OnePointFive,
+            2 => UartStopBits::Two,
+            _ => {
+                errors |= UART_CONFIG_ERROR_STOP_BITS;
+                UartStopBits::One
+            }
+        };
+        if errors != 0 {
+            return Err(errors);
+        }
+        Ok(Self {
+            baudrate,
+            data_bits,
+            parity,
+            stop_bits,
+        })
+    }
+}
+
+/// Error of the received data.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum UartError {
+    Overrun,
+    Framing,
+    Parity,
+}
+
+/// UART connected to the target.
+pub trait UartIo {
+    /// Applies the line settings and returns the actual baudrate.
+    /// Returns the `UART_CONFIG_ERROR_*` bits of the unsupported settings on failure.
+    fn configure(&mut self, config: &UartLineConfig) -> Result<u32, u8>;
+    /// Returns a received byte. `nb::Error::Other` means the data was lost or broken.
+    fn read(&mut self) -> nb::Result<u8, UartError>;
+    fn write(&mut self, data: u8) -> nb::Result<(), ()>;
+}
+
+/// `UartIo` of the probe without the UART to the target.
+pub struct NoUart;
+
+impl UartIo for NoUart {
+    fn configure(&mut self, _config: &UartLineConfig) -> Result<u32, u8> {
+        Err(UART_CONFIG_ERROR_DATA_BITS | UART_CONFIG_ERROR_PARITY | UART_CONFIG_ERROR_STOP_BITS)
+    }
+
+    fn read(&mut self) -> nb::Result<u8, UartError> {
+        Err(nb::Error::WouldBlock)
+    }
+
+    fn write(&mut self, _data: u8) -> nb::Result<(), ()> {
+        Err(nb::Error::WouldBlock)
+    }
+}
+
+/// Target UART communication state with the receive and transmit buffers.
+pub struct DapUart<U: UartIo> {
+    io: U,
+    rx: Deque<u8, UART_RX_BUFFER_SIZE>,
+    tx: Deque<u8, UART_TX_BUFFER_SIZE>,
+    transport: u8,
+    usb_com_port: bool,
+    rx_enabled: bool,
+    tx_enabled: bool,
+    data_lost: bool,
+    framing_error: bool,
+    parity_error: bool,
+}
+
+impl<U: UartIo> DapUart<U> {
+    /// `usb_com_port` tells whether the transport has the USB COM port bridged to the UART. (UART transport 1)
+    /// The UART is bridged to the COM port until another transport is selected.
+    pub fn new(io: U, usb_com_port: bool) -> Self {
+        Self {
+            io,
+            rx: Deque::new(),
+            tx: Deque::new(),
+            transport: if usb_com_port { UART_TRANSPORT_USB_COM_PORT } else { UART_TRANSPORT_NONE },
+            usb_com_port,
+            rx_enabled: usb_com_port,
+            tx_enabled: usb_com_port,
+            data_lost: false,
+            framing_error: false,
+            parity_error: false,
+        }
+    }
+
+    pub fn io(&mut self) -> &mut U {
+        &mut self.io
+    }
+
+    pub fn has_usb_com_port(&self) -> bool {
+        self.usb_com_port
+    }
+
+    pub fn transport(&self) -> u8 {
+        self.transport
+    }
+
+    /// Number of bytes received and not read yet.
+    pub fn rx_len(&self) -> usize {
+        self.rx.len()
+    }
+
+    /// Number of bytes written and not transmitted yet.
+    pub fn tx_len(&self) -> usize {
+        self.tx.len()
+    }
+
+    /// Free space in the transmit buffer.
+    pub fn tx_space(&self) -> usize {
+        self.tx.capacity() - self.tx.len()
+    }
+
+    pub fn set_transport(&mut self, transport: u8) -> bool {
+        let supported = match transport {
+            UART_TRANSPORT_NONE | UART_TRANSPORT_DAP_COMMAND => true,
+            UART_TRANSPORT_USB_COM_PORT => self.usb_com_port,
+            _ => false,
+        };
+        if !supported {
+            return false;
+        }
+        if transport != self.transport {
+            // 別のトランスポートのデータが混ざらないように捨てる
+            self.rx.clear();
+            self.tx.clear();
+            self.transport = transport;
+        }
+        if transport == UART_TRANSPORT_USB_COM_PORT {
+            // COMポートはホストが開いたらすぐに送受信できるようにする
+            self.rx_enabled = true;
+            self.tx_enabled = true;
+        }
+        true
+    }
+
+    /// Applies the line settings and returns the actual baudrate,
+    /// or the `UART_CONFIG_ERROR_*` bits of the unsupported settings.
+    pub fn configure(&mut self, config: &UartLineConfig) -> Result<u32, u8> {
+        // 送信途中のデータは設定を変える前のボーレートで送る
+        self.poll();
+        self.io.configure(config)
+    }
+
+    /// Enables, disables or flushes the receiver and the transmitter by `UART_CONTROL_*` bits.
+    pub fn control(&mut self, control: u8) {
+        if control & UART_CONTROL_RX_DISABLE != 0 {
+            self.rx_enabled = false;
+        }
+        if control & UART_CONTROL_RX_ENABLE != 0 {
+            self.rx_enabled = true;
+        }
+        if control & UART_CONTROL_RX_FLUSH != 0 {
+            self.rx.clear();
+            self.data_lost = false;
+        }
+        if control & UART_CONTROL_TX_DISABLE != 0 {
+            self.tx_enabled = false;
+        }
+        if control & UART_CONTROL_TX_ENABLE != 0 {
+            self.tx_enabled = true;
+        }
+        if control & UART_CONTROL_TX_FLUSH != 0 {
+            self.tx.clear();
+        }
+    }
+
+    /// Returns the UART status (`UART_STATUS_*`) and clears the error bits.
+    pub fn take_status(&mut self) -> u8 {
+        let mut status = 0;
+        if self.rx_enabled {
+            status |= UART_STATUS_RX_ENABLED;
+        }
+        if self.data_lost {
+            status |= UART_STATUS_RX_DATA_LOST;
+        }
+        if self.framing_error {
+            status |= UART_STATUS_FRAMING_ERROR;
+        }
+        if self.parity_error {
+            status |= UART_STATUS_PARITY_ERROR;
+        }
+        if self.tx_enabled {
+            status |= UART_STATUS_TX_ENABLED;
+        }
+        self.data_lost = false;
+        self.framing_error = false;
+        self.parity_error = false;
+        status
+    }
+
+    /// Moves the received data to the receive buffer, and the data in the transmit buffer to the UART.
+    pub fn poll(&mut self) {
+        if self.transport == UART_TRANSPORT_NONE {
+            return;
+        }
+        while self.rx_enabled {
+            match self.io.read() {
+                Ok(data) => {
+                    if self.rx.push_back(data).is_err() {
+                        // バッファが一杯なので新しいデータを捨てる
+                        self.data_lost = true;
+                    }
+                }
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(UartError::Overrun)) => self.data_lost = true,
+                Err(nb::Error::Other(UartError::Framing)) => self.framing_error = true,
+                Err(nb::Error::Other(UartError::Parity)) => self.parity_error = true,
+            }
+        }
+        while self.tx_enabled {
+            let data = match self.tx.front() {
+                Some(data) => *data,
+                None => break,
+            };
+            if self.io.write(data).is_err() {
+                // 送信FIFOが一杯なので次のpollで送る
+                break;
+            }
+            self.tx.pop_front();
+        }
+    }
+
+    /// Moves the oldest received data to `data` and returns the number of bytes moved.
+    pub fn read(&mut self, data: &mut [u8]) -> usize {
+        let length = data.len().min(self.rx.len());
+        for byte in data[..length].iter_mut() {
+            *byte = self.rx.pop_front().unwrap_or_default();
+        }
+        length
+    }
+
+    /// Appends `data` to the transmit buffer as many as possible and returns the number of bytes appended.
+    pub fn write(&mut self, data: &[u8]) -> usize {
+        data.iter().take_while(|byte| self.tx.push_back(**byte).is_ok()).count()
+    }
+}
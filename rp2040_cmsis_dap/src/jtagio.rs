@@ -0,0 +1,243 @@
+// Copyright 2023 Kenta Ida
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::dap_processor::DapError;
+use crate::swdio::*;
+
+pub const JTAG_MAX_DEVICES: usize = 8;
+
+// JTAG-DP instructions
+pub const JTAG_ABORT: u32 = 0x08;
+pub const JTAG_DPACC: u32 = 0x0a;
+pub const JTAG_APACC: u32 = 0x0b;
+pub const JTAG_IDCODE: u32 = 0x0e;
+
+/// JTAG scan chain configured by DAP_JTAG_Configure.
+#[derive(Clone, Copy)]
+pub struct JtagConfig {
+    pub count: usize,
+    pub index: usize,
+    pub ir_length: [u8; JTAG_MAX_DEVICES],
+    pub ir_before: [u16; JTAG_MAX_DEVICES],
+    pub ir_after: [u16; JTAG_MAX_DEVICES],
+}
+
+impl Default for JtagConfig {
+    fn default() -> Self {
+        let mut config = Self {
+            count: 0,
+            index: 0,
+            ir_length: [0; JTAG_MAX_DEVICES],
+            ir_before: [0; JTAG_MAX_DEVICES],
+            ir_after: [0; JTAG_MAX_DEVICES],
+        };
+        // ARM JTAG-DPが1つだけつながっているものとする
+        config.configure(&[4]).ok();
+        config
+    }
+}
+
+impl JtagConfig {
+    /// Sets the IR length of each device in the scan chain, the first one is the nearest to TDO.
+    pub fn configure(&mut self, ir_lengths: &[u8]) -> core::result::Result<(), DapError> {
+        if ir_lengths.is_empty() || ir_lengths.len() > JTAG_MAX_DEVICES || ir_lengths.contains(&0) {
+            return Err(DapError::InvalidCommand);
+        }
+        let total = ir_lengths.iter().map(|length| *length as u16).sum::<u16>();
+        let mut before = 0;
+        for (index, length) in ir_lengths.iter().enumerate() {
+            self.ir_length[index] = *length;
+            self.ir_before[index] = before;
+            before += *length as u16;
+            self.ir_after[index] = total - before;
+        }
+        self.count = ir_lengths.len();
+        self.index = 0;
+        Ok(())
+    }
+}
+
+pub trait JtagIo {
+    fn jtag_connect(&mut self);
+    fn jtag_disconnect(&mut self);
+    /// Clocks `count` (up to 64) TCK cycles with TMS fixed to `tms`, shifting out `tdi` LSB first.
+    /// Returns the bits sampled on TDO, the first one in LSB.
+    fn jtag_sequence(&mut self, config: &SwdIoConfig, count: usize, tms: bool, tdi: u64) -> u64;
+}
+
+/// Shifts `count` bits of `tdi` with TMS low except the last bit, which is shifted with `exit` as TMS.
+/// Bits beyond 64 are shifted as 1 and their TDO are discarded.
+fn shift<T: JtagIo + ?Sized>(io: &mut T, config: &SwdIoConfig, count: usize, tdi: u64, exit: bool) -> u64 {
+    if count == 0 {
+        return 0;
+    }
+    let bits_of = |offset: usize| if offset < 64 { tdi >> offset } else { u64::MAX };
+    let mut tdo = 0;
+    let mut offset = 0;
+    while offset < count - 1 {
+        let bits = (count - 1 - offset).min(64);
+        let captured = io.jtag_sequence(config, bits, false, bits_of(offset));
+        if offset < 64 {
+            tdo |= captured << offset;
+        }
+        offset += bits;
+    }
+    let captured = io.jtag_sequence(config, 1, exit, bits_of(offset) & 1);
+    if offset < 64 {
+        tdo |= captured << offset;
+    }
+    tdo
+}
+
+/// Writes `ir` into the instruction register of the selected device, putting the others in BYPASS.
+pub fn jtag_ir<T: JtagIo + ?Sized>(io: &mut T, config: &SwdIoConfig, jtag: &JtagConfig, ir: u32) {
+    let index = jtag.index;
+    let ir_after = jtag.ir_after[index] as usize;
+    io.jtag_sequence(config, 2, true, 0); // Select-DR-Scan, Select-IR-Scan
+    io.jtag_sequence(config, 2, false, 0); // Capture-IR, Shift-IR
+    shift(io, config, jtag.ir_before[index] as usize, u64::MAX, false);
+    shift(io, config, jtag.ir_length[index] as usize, ir as u64, ir_after == 0);
+    shift(io, config, ir_after, u64::MAX, true);
+    io.jtag_sequence(config, 1, true, 0); // Update-IR
+    io.jtag_sequence(config, 1, false, 0); // Run-Test/Idle
+}
+
+/// Performs a DPACC/APACC scan. The instruction must be selected by `jtag_ir` in advance.
+///
+/// Returns the data captured by the scan, which is the result of the previous read.
+/// The ACK is converted to the DAP_Transfer response bits. (OK/FAULT -> OK, WAIT -> WAIT)
+pub fn jtag_transfer<T: JtagIo + ?Sized>(
+    io: &mut T,
+    config: &SwdIoConfig,
+    jtag: &JtagConfig,
+    request: SwdRequest,
+    data: u32,
+) -> core::result::Result<u32, DapError> {
+    io.jtag_sequence(config, 1, true, 0); // Select-DR-Scan
+    io.jtag_sequence(config, 2, false, 0); // Capture-DR, Shift-DR
+    // 前にあるデバイスのBYPASSレジスタ
+    shift(io, config, jtag.index, u64::MAX, false);
+    // RnW, A2, A3
+    let ack = shift(io, config, 3, ((request >> 1) & 0x07) as u64, false) as u8;
+    let ack = ((ack & 0x01) << 1) | ((ack & 0x02) >> 1) | (ack & 0x04);
+    if ack != DAP_TRANSFER_OK {
+        io.jtag_sequence(config, 2, true, 0); // Exit1-DR, Update-DR
+        io.jtag_sequence(config, 1, false, 0); // Run-Test/Idle
+        return Err(DapError::SwdError(ack));
+    }
+    let devices_after = jtag.count - jtag.index - 1;
+    let value = shift(io, config, 32, data as u64, devices_after == 0) as u32;
+    // 後ろにあるデバイスのBYPASSレジスタ
+    shift(io, config, devices_after, u64::MAX, true);
+    io.jtag_sequence(config, 1, true, 0); // Update-DR
+    io.jtag_sequence(config, 1, false, 0); // Run-Test/Idle
+    let mut idle_cycles = config.idle_cycles as usize;
+    while idle_cycles > 0 {
+        let count = idle_cycles.min(64);
+        io.jtag_sequence(config, count, false, 0);
+        idle_cycles -= count;
+    }
+    Ok(value)
+}
+
+/// Reads the IDCODE of the selected device. `JTAG_IDCODE` must be selected by `jtag_ir` in advance.
+pub fn jtag_read_idcode<T: JtagIo + ?Sized>(io: &mut T, config: &SwdIoConfig, jtag: &JtagConfig) -> u32 {
+    io.jtag_sequence(config, 1, true, 0); // Select-DR-Scan
+    io.jtag_sequence(config, 2, false, 0); // Capture-DR, Shift-DR
+    shift(io, config, jtag.index, u64::MAX, false);
+    let idcode = shift(io, config, 32, 0, true) as u32;
+    io.jtag_sequence(config, 1, true, 0); // Update-DR
+    io.jtag_sequence(config, 1, false, 0); // Run-Test/Idle
+    idcode
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `JtagIo` which records TMS and TDI of each TCK cycle, and drives TDO from `tdo`.
+    #[derive(Default)]
+    struct RecordingJtag {
+        tms: Vec<bool>,
+        tdi: Vec<bool>,
+        tdo: Vec<bool>,
+    }
+
+    impl JtagIo for RecordingJtag {
+        fn jtag_connect(&mut self) {}
+        fn jtag_disconnect(&mut self) {}
+        fn jtag_sequence(&mut self, _config: &SwdIoConfig, count: usize, tms: bool, tdi: u64) -> u64 {
+            let mut captured = 0;
+            for bit in 0..count {
+                if self.tdo.get(self.tms.len()).copied().unwrap_or(false) {
+                    captured |= 1 << bit;
+                }
+                self.tms.push(tms);
+                self.tdi.push((tdi >> bit) & 1 != 0);
+            }
+            captured
+        }
+    }
+
+    /// Converts a string of '0' and '1' to bits, ignoring the other characters.
+    fn bits(bits: &str) -> Vec<bool> {
+        bits.chars().filter(|c| *c == '0' || *c == '1').map(|c| c == '1').collect()
+    }
+
+    /// Returns the bits of `value` LSB first.
+    fn bits_of(value: u32, count: usize) -> String {
+        (0..count).map(|bit| if (value >> bit) & 1 != 0 { '1' } else { '0' }).collect()
+    }
+
+    /// Scan chain of 3 devices with the IR lengths 4, 5 and 3, the middle one selected.
+    fn chain() -> JtagConfig {
+        let mut jtag = JtagConfig::default();
+        jtag.configure(&[4, 5, 3]).unwrap();
+        jtag.index = 1;
+        jtag
+    }
+
+    #[test]
+    fn configure_counts_ir_bits_on_both_sides() {
+        let jtag = chain();
+        assert_eq!(jtag.count, 3);
+        assert_eq!(jtag.ir_before[..3], [0, 4, 9]);
+        assert_eq!(jtag.ir_after[..3], [8, 3, 0]);
+        assert!(JtagConfig::default().configure(&[4, 0]).is_err());
+    }
+
+    #[test]
+    fn ir_scan_pads_the_other_devices_with_bypass() {
+        let mut io = RecordingJtag::default();
+        jtag_ir(&mut io, &SwdIoConfig::default(), &chain(), JTAG_DPACC);
+        // TDOに近いデバイス0のIRが先、デバイス2のIRが後。どちらもBYPASS (すべて1)
+        assert_eq!(io.tdi, bits("0000 1111 01010 111 0 0"));
+        assert_eq!(io.tms, bits("1100 0000 00000 001 1 0"));
+    }
+
+    #[test]
+    fn dr_scan_pads_the_other_devices_with_bypass() {
+        // ACKはOK (0b010)。データはデバイス0のBYPASSの分だけ遅れて出てくる
+        let mut io = RecordingJtag {
+            tdo: bits(&format!("000 0 010 {}", bits_of(0x8765_4321, 32))),
+            ..Default::default()
+        };
+        let value = jtag_transfer(&mut io, &SwdIoConfig::default(), &chain(), DAP_TRANSFER_A2, 0x1234_5678);
+        assert_eq!(value, Ok(0x8765_4321));
+        assert_eq!(io.tdi, bits(&format!("000 1 010 {} 1 0 0", bits_of(0x1234_5678, 32))));
+        assert_eq!(io.tms, bits(&format!("100 0 000 {} 1 1 0", bits_of(0, 32))));
+    }
+}
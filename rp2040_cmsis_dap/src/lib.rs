@@ -0,0 +1,45 @@
+// Copyright 2023 Kenta Ida
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! CMSIS-DAP probe implementation.
+//!
+//! The command processor (`dap_processor`) does not depend on the USB stack nor the RP2040,
+//! so it can be built for the host with the `std` feature enabled:
+//!
+//! ```text
+//! cargo test --lib --features std --target x86_64-unknown-linux-gnu
+//! ```
+//!
+//! The RP2040 specific dependencies are only used for `target_os = "none"`.
+
+#![cfg_attr(not(feature = "std"), no_std)]
+
+pub mod bitbang_swdio;
+pub mod cmsis_dap;
+pub mod dap_info;
+pub mod dap_processor;
+pub mod dap_uart;
+pub mod jtagio;
+pub mod manchester;
+#[cfg(target_os = "none")]
+pub mod rp2040_uart;
+#[cfg(any(test, feature = "std"))]
+pub mod sim_target;
+pub mod swdio;
+pub mod swo;
+pub mod target_reset;
+#[cfg(target_os = "none")]
+pub mod target_uart;
@@ -17,12 +17,22 @@
 #![no_std]
 #![no_main]
 
-mod cmsis_dap;
-use cmsis_dap::CmsisDapInterface;
-mod swdio;
-use swdio::SwdIo;
+use rp2040_cmsis_dap::cmsis_dap::CmsisDapInterface;
+use rp2040_cmsis_dap::dap_info::*;
+use rp2040_cmsis_dap::swo::DualModeSwo;
+use rp2040_cmsis_dap::target_reset::reset_by_nreset;
+use rp2040_cmsis_dap::target_uart::TargetUart;
+mod pio_swdio;
+use pio_swdio::PioSwdIo;
+mod pio_swo;
+use pio_swo::PioManchesterSwo;
+mod uart_swo;
+use uart_swo::UartSwo;
 
+use hal::gpio::{FunctionPio0, FunctionUart};
 use hal::pac;
+use hal::pio::PIOExt;
+use hal::Clock;
 use panic_halt as _;
 use rp_pico::hal;
 
@@ -35,6 +45,7 @@ fn main() -> ! {
 
     let mut resets = pac.RESETS;
 
+    let sio = hal::Sio::new(pac.SIO);
     let mut watchdog = hal::Watchdog::new(pac.WATCHDOG);
     // クロックを初期化
     let clocks = hal::clocks::init_clocks_and_plls(
@@ -48,6 +59,50 @@ fn main() -> ! {
     )
     .ok()
     .unwrap();
+    // GPIOを初期化
+    let pins = rp_pico::Pins::new(
+        pac.IO_BANK0,
+        pac.PADS_BANK0,
+        sio.gpio_bank0,
+        &mut resets,
+    );
+    // SWCLK = GPIO2, SWDIO = GPIO3 をPIO0に割り当てる
+    const SWCLK_PIN: u8 = 2;
+    const SWDIO_PIN: u8 = 3;
+    let _swclk = pins.gpio2.into_mode::<FunctionPio0>();
+    let _swdio = pins.gpio3.into_mode::<FunctionPio0>();
+    // nRESET = GPIO4 はSIOで駆動する (解除時はプルアップ)
+    const NRESET_PIN: u8 = 4;
+    let _nreset = pins.gpio4.into_pull_up_input();
+    let (mut pio0, sm0, sm1, _, _) = pac.PIO0.split(&mut resets);
+    let swdio = PioSwdIo::new(
+        &mut pio0,
+        sm0,
+        SWCLK_PIN,
+        SWDIO_PIN,
+        clocks.system_clock.freq().to_Hz(),
+    )
+    .with_reset_pin(NRESET_PIN);
+    // SWO = GPIO9 (UART1 RX) をUART1に割り当てる。GPIO8 (UART1 TX) は使わない
+    const SWO_PIN: u8 = 9;
+    let swo_uart = hal::uart::UartPeripheral::new(
+        pac.UART1,
+        (pins.gpio8.into_mode::<FunctionUart>(), pins.gpio9.into_mode::<FunctionUart>()),
+        &mut resets,
+    );
+    // UARTモードはUART1、ManchesterモードはPIO0で受信する
+    // PIOはピンの機能に関係なく入力を読めるので、同じピンを使える
+    let swo = DualModeSwo::new(
+        UartSwo::new(swo_uart, clocks.peripheral_clock.freq().to_Hz()),
+        PioManchesterSwo::new(&mut pio0, sm1, SWO_PIN, clocks.system_clock.freq().to_Hz()),
+    );
+    // ターゲットのUARTはGPIO0 (UART0 TX), GPIO1 (UART0 RX) につなぐ
+    let target_uart = hal::uart::UartPeripheral::new(
+        pac.UART0,
+        (pins.gpio0.into_mode::<FunctionUart>(), pins.gpio1.into_mode::<FunctionUart>()),
+        &mut resets,
+    );
+    let target_uart = TargetUart::new(target_uart, clocks.peripheral_clock.freq().to_Hz());
     // UsbBusを初期化
     let usb_bus = hal::usb::UsbBus::new(
         pac.USBCTRL_REGS,   // RP2040のUSBペリフェラルのレジスタ
@@ -57,16 +112,51 @@ fn main() -> ! {
         &mut resets,        // サブシステムのリセット・レジスタ
     );
     const MAX_PACKET_SIZE: u8 = 64;
+    // USBデバイスの文字列。DAP_Infoでも同じものを返す
+    const USB_MANUFACTURER: &str = "test manufacturer";
+    #[cfg(not(feature = "hid"))]
+    const USB_PRODUCT: &str = "test product";
+    // CMSIS-DAP v1のホストは製品名に"CMSIS-DAP"を含むデバイスを探す
+    #[cfg(feature = "hid")]
+    const USB_PRODUCT: &str = "test product CMSIS-DAP";
+    const USB_SERIAL_NUMBER: &str = "serial number";
+    // ホストが応答を待たずに送信できるパケット数
+    const DAP_PACKET_COUNT: usize = 4;
     // UsbBusAllocatorを構築
     // ※UsbBusAllocatorは内部可変性を持つ型なのでmutでなくて良い
     let usb_bus_allocator = UsbBusAllocator::new(usb_bus);
-    // usb-serialクレートのSerialPortを構築
-    let mut cmsis_dap = CmsisDapInterface::new(&usb_bus_allocator, MAX_PACKET_SIZE as u16);
+    // CMSIS-DAPインターフェースを構築
+    // ターゲットのリセットはnRESETピンで行う
+    let cmsis_dap = CmsisDapInterface::<_, _, DAP_PACKET_COUNT>::new(&usb_bus_allocator, MAX_PACKET_SIZE as u16, swdio)
+        .with_info(DapInfo {
+            vendor: USB_MANUFACTURER,
+            product: USB_PRODUCT,
+            serial_number: USB_SERIAL_NUMBER,   // USBのシリアル番号と同じにする
+            target_board_vendor: "Raspberry Pi",
+            target_board_name: "Raspberry Pi Pico",
+            capabilities: DAP_CAP_SWD
+                | DAP_CAP_SWO_UART
+                | DAP_CAP_SWO_MANCHESTER
+                | DAP_CAP_ATOMIC_COMMANDS
+                | DAP_CAP_SWO_STREAMING_TRACE
+                | DAP_CAP_UART_COMMUNICATION_PORT
+                | DAP_CAP_UART_USB_COM_PORT,
+            ..Default::default()
+        })
+        .with_reset_target(reset_by_nreset)
+        .with_swo(&usb_bus_allocator, MAX_PACKET_SIZE as u16, swo);
+    // HIDインターフェース (CMSIS-DAP v1) を追加する
+    #[cfg(feature = "hid")]
+    let cmsis_dap = cmsis_dap.with_hid(&usb_bus_allocator);
+    // ターゲットのUARTはDAP_UARTコマンドとCOMポート (CDC-ACM) の両方から使える
+    let mut cmsis_dap = cmsis_dap
+        .with_usb_com_port(&usb_bus_allocator)
+        .with_uart(target_uart);
     // UsbDeviceを構築 VID=0x6666, PID=0x4444 (prototype product)
     let mut usb_device = UsbDeviceBuilder::new(&usb_bus_allocator, UsbVidPid(0x6666, 0x4444))
-        .manufacturer("test manufacturer")  // Manufacturer  = "test manufacturer"
-        .product("test product")            // Product       = "test product"
-        .serial_number("serial number")     // Serial Number = "serial number" 
+        .manufacturer(USB_MANUFACTURER)     // Manufacturer  = "test manufacturer"
+        .product(USB_PRODUCT)               // Product       = "test product"
+        .serial_number(USB_SERIAL_NUMBER)   // Serial Number = "serial number" 
         .composite_with_iads()              // IADを使った複合デバイスとする
         .max_packet_size_0(MAX_PACKET_SIZE) // 最大パケットサイズ (64バイト)
         .build();                           // 上記の設定でUsbDeviceを構築
@@ -0,0 +1,241 @@
+// Copyright 2023 Kenta Ida
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+/// Shortest half bit accepted as the start bit, in ticks.
+pub const MANCHESTER_MIN_HALF_BIT_TICKS: u32 = 2;
+
+#[derive(Clone, Copy, PartialEq)]
+enum State {
+    // パケットの開始 (スタートビット) を待っている
+    Idle,
+    // ビットの中央のエッジの直後
+    Mid,
+    // ビットの境界のエッジの直後
+    Boundary,
+}
+
+/// Decoder of the Manchester encoded SWO (TPIU) from the widths of the pulses on the SWO pin.
+///
+/// A packet starts with the start bit (logical 1) after the line is idle (low).
+/// A logical 1 is high in the first half of the bit and low in the second half, and vice versa for 0.
+/// Data bits follow LSB first. The bit rate is detected from the start bit and tracked through the packet.
+pub struct ManchesterDecoder {
+    tick_hz: u32,
+    state: State,
+    // パケット中のパルス幅の合計と、それが半ビット何個分かの数
+    packet_ticks: u64,
+    packet_half_bits: u64,
+    data: u8,
+    bit_count: u8,
+    // 最後に受信したパケットから求めたボーレート
+    baudrate: u32,
+}
+
+impl ManchesterDecoder {
+    /// `tick_hz` is the frequency of the ticks in which the pulse widths are measured.
+    pub fn new(tick_hz: u32) -> Self {
+        Self {
+            tick_hz,
+            state: State::Idle,
+            packet_ticks: 0,
+            packet_half_bits: 0,
+            data: 0,
+            bit_count: 0,
+            baudrate: 0,
+        }
+    }
+
+    /// Discards the packet being decoded.
+    pub fn reset(&mut self) {
+        self.state = State::Idle;
+    }
+
+    pub fn tick_hz(&self) -> u32 {
+        self.tick_hz
+    }
+
+    /// Bit rate detected from the last packet, or 0 if no packet has been received.
+    pub fn baudrate(&self) -> u32 {
+        self.baudrate
+    }
+
+    /// Feeds a pulse of `level` lasting `ticks`. Returns a byte when its last bit is decoded.
+    pub fn pulse(&mut self, level: bool, ticks: u32) -> Option<u8> {
+        if self.state == State::Idle {
+            // スタートビットの前半 (High) の幅から半ビットの長さを求める
+            if level && ticks >= MANCHESTER_MIN_HALF_BIT_TICKS {
+                self.state = State::Mid;
+                self.packet_ticks = ticks as u64;
+                self.packet_half_bits = 1;
+                self.data = 0;
+                self.bit_count = 0;
+            }
+            return None;
+        }
+        // 半ビット1個分か2個分かを、これまでの平均の半ビットの長さと比べて判定する
+        let ticks2 = ticks as u64 * 2 * self.packet_half_bits;
+        let half_bits = if ticks2 < 3 * self.packet_ticks {
+            1
+        } else if ticks2 < 5 * self.packet_ticks {
+            2
+        } else {
+            // アイドル状態になったのでパケットの終わり
+            self.state = State::Idle;
+            return None;
+        };
+        let bit = match (self.state, half_bits) {
+            (State::Mid, 1) => {
+                self.state = State::Boundary;
+                None
+            }
+            (State::Boundary, 1) | (State::Mid, 2) => {
+                // 次のビットの中央に来た。ビットの前半のレベルがビットの値
+                self.state = State::Mid;
+                Some(level)
+            }
+            _ => {
+                // 境界から半ビット2個分エッジがないのは符号化の誤り。次のパケットを待つ
+                self.state = State::Idle;
+                return None;
+            }
+        };
+        self.packet_ticks += ticks as u64;
+        self.packet_half_bits += half_bits;
+        self.baudrate = (self.tick_hz as u64 * self.packet_half_bits / (2 * self.packet_ticks)) as u32;
+        let bit = bit?;
+        self.data |= (bit as u8) << self.bit_count;
+        self.bit_count += 1;
+        if self.bit_count == 8 {
+            let data = self.data;
+            self.data = 0;
+            self.bit_count = 0;
+            Some(data)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TICK_HZ: u32 = 125_000_000;
+
+    /// Returns the line levels of a packet in half bits: start bit, then the bits of `bytes` LSB first.
+    fn encode(bytes: &[u8]) -> Vec<bool> {
+        let mut levels = vec![true, false];
+        for byte in bytes {
+            for index in 0..8 {
+                let bit = byte & (1 << index) != 0;
+                levels.extend([bit, !bit]);
+            }
+        }
+        levels
+    }
+
+    /// Converts the levels in half bits to the pulses between the edges, with the idle line before and after them.
+    fn pulses(levels: &[bool], half_bit_ticks: u32) -> Vec<(bool, u32)> {
+        let idle = [false; 8];
+        let mut pulses: Vec<(bool, u32)> = Vec::new();
+        for level in idle.iter().chain(levels).chain(idle.iter()) {
+            match pulses.last_mut() {
+                Some((last_level, ticks)) if last_level == level => *ticks += half_bit_ticks,
+                _ => pulses.push((*level, half_bit_ticks)),
+            }
+        }
+        pulses
+    }
+
+    fn decode(decoder: &mut ManchesterDecoder, pulses: &[(bool, u32)]) -> Vec<u8> {
+        pulses.iter().filter_map(|(level, ticks)| decoder.pulse(*level, *ticks)).collect()
+    }
+
+    #[test]
+    fn decodes_bytes() {
+        let bytes = [0x00, 0xff, 0xa5, 0x3c, 0x01, 0x80];
+        let mut decoder = ManchesterDecoder::new(TICK_HZ);
+        assert_eq!(decode(&mut decoder, &pulses(&encode(&bytes), 125)), bytes);
+    }
+
+    #[test]
+    fn detects_baudrate() {
+        let mut decoder = ManchesterDecoder::new(TICK_HZ);
+        assert_eq!(decoder.baudrate(), 0);
+        decode(&mut decoder, &pulses(&encode(&[0x55]), 125));
+        assert_eq!(decoder.baudrate(), 500_000);
+        decode(&mut decoder, &pulses(&encode(&[0x55]), 10));
+        assert_eq!(decoder.baudrate(), 6_250_000);
+    }
+
+    #[test]
+    fn tracks_jitter() {
+        // 半ビットの長さが±10%揺れても復号できる
+        let mut pulses = pulses(&encode(&[0x12, 0x34]), 100);
+        for (index, (_, ticks)) in pulses.iter_mut().enumerate() {
+            if index % 2 == 0 {
+                *ticks = *ticks * 9 / 10;
+            } else {
+                *ticks = *ticks * 11 / 10;
+            }
+        }
+        let mut decoder = ManchesterDecoder::new(TICK_HZ);
+        assert_eq!(decode(&mut decoder, &pulses), [0x12, 0x34]);
+    }
+
+    #[test]
+    fn packets_end_at_idle() {
+        let mut decoder = ManchesterDecoder::new(TICK_HZ);
+        // 1バイトに満たないビットはアイドルで捨てられ、次のパケットはビット0から始まる
+        let mut levels = encode(&[0xff]);
+        levels.truncate(2 + 2 * 4);
+        let mut input = pulses(&levels, 50);
+        input.extend(pulses(&encode(&[0x81]), 50));
+        input.extend(pulses(&encode(&[0x7e]), 50));
+        assert_eq!(decode(&mut decoder, &input), [0x81, 0x7e]);
+    }
+
+    #[test]
+    fn short_start_pulse_is_ignored() {
+        let mut decoder = ManchesterDecoder::new(TICK_HZ);
+        assert_eq!(decoder.pulse(true, MANCHESTER_MIN_HALF_BIT_TICKS - 1), None);
+        assert_eq!(decoder.pulse(false, 1000), None);
+        assert_eq!(decode(&mut decoder, &pulses(&encode(&[0x5a]), 20)), [0x5a]);
+    }
+
+    #[test]
+    fn long_pulse_in_packet_is_rejected() {
+        let mut decoder = ManchesterDecoder::new(TICK_HZ);
+        // データの途中で半ビット3個分のパルスが来たらパケットを捨てる
+        let mut input = pulses(&encode(&[0x00]), 40);
+        let index = input.len() / 2;
+        input[index].1 = 3 * 40;
+        assert!(decode(&mut decoder, &input).is_empty());
+        assert_eq!(decode(&mut decoder, &pulses(&encode(&[0xc3]), 40)), [0xc3]);
+    }
+
+    #[test]
+    fn missing_mid_bit_edge_is_rejected() {
+        let mut decoder = ManchesterDecoder::new(TICK_HZ);
+        // スタートビットの後、境界から半ビット2個分エッジがない
+        assert_eq!(decoder.pulse(false, 200), None);
+        assert_eq!(decoder.pulse(true, 20), None);
+        assert_eq!(decoder.pulse(false, 20), None);
+        assert_eq!(decoder.pulse(true, 40), None);
+        // 符号化の誤りで待ち状態に戻るので、後続のパルスはスタートビットとして扱われる
+        assert_eq!(decode(&mut decoder, &pulses(&encode(&[0xa5]), 20)), [0xa5]);
+    }
+}
@@ -0,0 +1,310 @@
+// Copyright 2023 Kenta Ida
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use pio::{Instruction, InstructionOperands, JmpCondition, SetDestination};
+use rp_pico::hal::pac;
+use rp_pico::hal::pio::{
+    PIOBuilder, PIOExt, PinDir, Running, Rx, ShiftDirection, StateMachine, StateMachineIndex, Tx,
+    UninitStateMachine, PIO,
+};
+
+use rp2040_cmsis_dap::dap_processor::DapError;
+use rp2040_cmsis_dap::swdio::*;
+
+// 1ビットあたりのPIOのサイクル数
+const PIO_CYCLES_PER_BIT: u32 = 2;
+
+#[derive(Clone, Copy, PartialEq)]
+enum Mode {
+    In,
+    Out,
+}
+
+/// `SwdIo` implementation which drives SWCLK/SWDIO by a PIO state machine.
+///
+/// The SWCLK and SWDIO pins must be switched to the function of the PIO block in advance.
+pub struct PioSwdIo<P: PIOExt, SM: StateMachineIndex> {
+    sm: Option<StateMachine<(P, SM), Running>>,
+    rx: Rx<(P, SM)>,
+    tx: Tx<(P, SM)>,
+    in_offset: u8,
+    out_offset: u8,
+    mode: Mode,
+    swclk_pin: u8,
+    swdio_pin: u8,
+    reset_pin: Option<u8>,
+    system_clock_hz: u32,
+}
+
+impl<P: PIOExt, SM: StateMachineIndex> PioSwdIo<P, SM> {
+    pub fn new(
+        pio: &mut PIO<P>,
+        sm: UninitStateMachine<(P, SM)>,
+        swclk_pin: u8,
+        swdio_pin: u8,
+        system_clock_hz: u32,
+    ) -> Self {
+        // SWCLKをサイドセット、SWDIOをデータ入出力に割り当てる
+        // ホストからは (ビット数 - 1) とデータをTX FIFOに書き込み、完了をRX FIFOで待つ
+        let program = pio_proc::pio_asm!(
+            ".side_set 1 opt",
+            "public in_posedge:",
+            "    set pindirs, 0     side 0",    // SWDIOを入力にする
+            "    pull",                         // ビット数 - 1
+            "    mov x, osr",
+            "in_bitloop:",
+            "    in pins, 1         side 1",    // 立ち上がりエッジで読み込み
+            "    jmp x-- in_bitloop side 0",
+            "    push",                         // 読み込んだデータを返す
+            "    jmp in_posedge",
+            "public out_negedge:",
+            "    set pindirs, 1     side 0",    // SWDIOを出力にする
+            "    pull",                         // ビット数 - 1
+            "    mov x, osr",
+            "    pull",                         // 書き込むデータ
+            "out_bitloop:",
+            "    out pins, 1        side 0",    // 立ち下がりエッジで出力
+            "    jmp x-- out_bitloop side 1",
+            "    set pins, 1        side 0",    // アイドル時はSWDIO = High
+            "    push",                         // 完了を通知
+            "    jmp out_negedge",
+        );
+        let installed = pio.install(&program.program).unwrap();
+        let in_offset = installed.offset() + program.public_defines.in_posedge as u8;
+        let out_offset = installed.offset() + program.public_defines.out_negedge as u8;
+        let (mut sm, rx, tx) = PIOBuilder::from_program(installed)
+            .side_set_pin_base(swclk_pin)
+            .set_pins(swdio_pin, 1)
+            .out_pins(swdio_pin, 1)
+            .in_pin_base(swdio_pin)
+            .out_shift_direction(ShiftDirection::Right)
+            .in_shift_direction(ShiftDirection::Right)
+            .clock_divisor_fixed_point(1, 0)
+            .build(sm);
+        // 接続されるまではSWCLK, SWDIOともにハイ・インピーダンスにしておく
+        sm.set_pindirs([(swclk_pin, PinDir::Input), (swdio_pin, PinDir::Input)]);
+        let mut swdio = Self {
+            sm: Some(sm.start()),
+            rx,
+            tx,
+            in_offset,
+            out_offset,
+            mode: Mode::In,
+            swclk_pin,
+            swdio_pin,
+            reset_pin: None,
+            system_clock_hz,
+        };
+        let mut config = SwdIoConfig::default();
+        swdio.swj_clock(&mut config, 1_000_000).ok();
+        swdio
+    }
+
+    /// Uses the pin as nRESET. The pin must be switched to the SIO function in advance.
+    ///
+    /// nRESET is driven low when asserted and released (Hi-Z) when deasserted.
+    pub fn with_reset_pin(mut self, reset_pin: u8) -> Self {
+        let sio = Self::sio();
+        sio.gpio_oe_clr.write(|w| unsafe { w.bits(1 << reset_pin) });
+        sio.gpio_out_clr.write(|w| unsafe { w.bits(1 << reset_pin) });
+        self.reset_pin = Some(reset_pin);
+        self
+    }
+
+    fn sio() -> &'static pac::sio::RegisterBlock {
+        // SIOのセット/クリアレジスタを使い、nRESETピンのビットのみ書き換える
+        unsafe { &*pac::SIO::ptr() }
+    }
+
+    fn sm(&mut self) -> &mut StateMachine<(P, SM), Running> {
+        self.sm.as_mut().unwrap()
+    }
+
+    fn set_pindirs(&mut self, dir: PinDir) {
+        // ピンの方向を変えるためにステートマシンを一旦止める
+        if let Some(sm) = self.sm.take() {
+            let mut sm = sm.stop();
+            sm.set_pindirs([(self.swclk_pin, dir), (self.swdio_pin, dir)]);
+            self.sm = Some(sm.start());
+        }
+    }
+
+    fn exec(&mut self, operands: InstructionOperands) {
+        self.sm().exec_instruction(Instruction {
+            operands,
+            delay: 0,
+            side_set: None,
+        });
+    }
+
+    fn set_mode(&mut self, mode: Mode) {
+        if self.mode != mode {
+            // ステートマシンはpullで停止しているので、入力側・出力側の処理の先頭にジャンプさせる
+            let address = match mode {
+                Mode::In => self.in_offset,
+                Mode::Out => self.out_offset,
+            };
+            self.exec(InstructionOperands::JMP {
+                condition: JmpCondition::Always,
+                address,
+            });
+            self.mode = mode;
+        }
+    }
+
+    fn write_fifo(&mut self, value: u32) {
+        while !self.tx.write(value) {}
+    }
+
+    fn read_fifo(&mut self) -> u32 {
+        loop {
+            if let Some(value) = self.rx.read() {
+                return value;
+            }
+        }
+    }
+}
+
+impl<P: PIOExt, SM: StateMachineIndex> SwdBitIo for PioSwdIo<P, SM> {
+    fn write_bits(&mut self, _config: &SwdIoConfig, count: usize, data: u32) {
+        if count == 0 {
+            return;
+        }
+        self.set_mode(Mode::Out);
+        self.write_fifo(count as u32 - 1);
+        self.write_fifo(data);
+        self.read_fifo();
+    }
+
+    fn read_bits(&mut self, _config: &SwdIoConfig, count: usize) -> u32 {
+        if count == 0 {
+            return 0;
+        }
+        self.set_mode(Mode::In);
+        self.write_fifo(count as u32 - 1);
+        // 右シフトで読み込んでいるので、読み込んだビットはMSB側に詰まっている
+        self.read_fifo() >> (32 - count)
+    }
+}
+
+impl<P: PIOExt, SM: StateMachineIndex> SwdIo for PioSwdIo<P, SM> {
+    fn connect(&mut self) {
+        self.set_pindirs(PinDir::Output);
+    }
+
+    fn disconnect(&mut self) {
+        self.set_pindirs(PinDir::Input);
+    }
+
+    fn swj_clock(
+        &mut self,
+        config: &mut SwdIoConfig,
+        frequency_hz: u32,
+    ) -> core::result::Result<(), DapError> {
+        let _ = config;
+        if frequency_hz == 0 {
+            return Err(DapError::InvalidCommand);
+        }
+        // 分周比を整数部16ビット、小数部8ビットの固定小数点で求める
+        let divisor = ((self.system_clock_hz as u64) << 8) / (frequency_hz as u64 * PIO_CYCLES_PER_BIT as u64);
+        let int = divisor >> 8;
+        let frac = divisor & 0xff;
+        if int == 0 || int > 0xffff {
+            // PIOのクロックで出せない周波数
+            return Err(DapError::InvalidCommand);
+        }
+        self.sm().clock_divisor_fixed_point(int as u16, frac as u8);
+        Ok(())
+    }
+
+    fn swj_sequence(&mut self, config: &SwdIoConfig, count: usize, data: &[u8]) {
+        write_sequence_bits(self, config, count, data);
+    }
+
+    fn swd_read_sequence(&mut self, config: &SwdIoConfig, count: usize, data: &mut [u8]) {
+        read_sequence_bits(self, config, count, data);
+    }
+
+    fn swd_write_sequence(&mut self, config: &SwdIoConfig, count: usize, data: &[u8]) {
+        write_sequence_bits(self, config, count, data);
+    }
+
+    fn swd_transfer(
+        &mut self,
+        config: &SwdIoConfig,
+        request: SwdRequest,
+        data: u32,
+    ) -> core::result::Result<u32, DapError> {
+        swd_transfer_bits(self, config, request, data)
+    }
+
+    fn enable_output(&mut self) {
+        self.exec(InstructionOperands::SET {
+            destination: SetDestination::PINDIRS,
+            data: 1,
+        });
+    }
+
+    fn disable_output(&mut self) {
+        self.exec(InstructionOperands::SET {
+            destination: SetDestination::PINDIRS,
+            data: 0,
+        });
+    }
+
+    fn set_swj_pins(&mut self, output: u8, select: u8) {
+        // SWCLKはステートマシンのサイドセットで駆動しているため、SWDIOとnRESETのみ操作できる
+        if select & SWJ_PIN_SWDIO_TMS != 0 {
+            self.exec(InstructionOperands::SET {
+                destination: SetDestination::PINS,
+                data: (output & SWJ_PIN_SWDIO_TMS != 0) as u8,
+            });
+        }
+        if let Some(reset_pin) = self.reset_pin {
+            if select & SWJ_PIN_NRESET != 0 {
+                let sio = Self::sio();
+                if output & SWJ_PIN_NRESET != 0 {
+                    // 解放してプルアップに任せる
+                    sio.gpio_oe_clr.write(|w| unsafe { w.bits(1 << reset_pin) });
+                } else {
+                    sio.gpio_oe_set.write(|w| unsafe { w.bits(1 << reset_pin) });
+                }
+            }
+        }
+    }
+
+    fn get_swj_pins(&mut self) -> u8 {
+        let levels = Self::sio().gpio_in.read().bits();
+        let mut pins = 0;
+        if levels & (1 << self.swclk_pin) != 0 {
+            pins |= SWJ_PIN_SWCLK_TCK;
+        }
+        if levels & (1 << self.swdio_pin) != 0 {
+            pins |= SWJ_PIN_SWDIO_TMS;
+        }
+        // nRESETのピンがなければ、BitbangSwdIoと同じくLowとして返す
+        if let Some(reset_pin) = self.reset_pin {
+            if levels & (1 << reset_pin) != 0 {
+                pins |= SWJ_PIN_NRESET;
+            }
+        }
+        pins
+    }
+
+    fn delay_us(&mut self, us: u32) {
+        cortex_m::asm::delay(self.system_clock_hz / 1_000_000 * us);
+    }
+}
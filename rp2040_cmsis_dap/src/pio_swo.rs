@@ -0,0 +1,148 @@
+// Copyright 2023 Kenta Ida
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use pio::{Instruction, InstructionOperands, JmpCondition};
+use rp_pico::hal::pio::{
+    PIOBuilder, PIOExt, Running, Rx, StateMachine, StateMachineIndex, Stopped, UninitStateMachine, PIO,
+};
+
+use rp2040_cmsis_dap::manchester::{ManchesterDecoder, MANCHESTER_MIN_HALF_BIT_TICKS};
+use rp2040_cmsis_dap::swo::{SwoMode, SwoReceiver};
+
+// パルス幅を数えるループ1回あたりのPIOのサイクル数
+const PIO_CYCLES_PER_TICK: u32 = 2;
+// パルスの終わりから次のパルスを数え始めるまでに数えられないサイクル数 (tick単位)
+const PULSE_OVERHEAD_TICKS: u32 = 2;
+
+enum State<P: PIOExt, SM: StateMachineIndex> {
+    Stopped(StateMachine<(P, SM), Stopped>),
+    Running(StateMachine<(P, SM), Running>),
+}
+
+/// `SwoReceiver` which captures Manchester encoded SWO by a PIO state machine.
+///
+/// The state machine measures the width of each pulse on the SWO pin, and `ManchesterDecoder` decodes them.
+/// The pin can be read by the PIO regardless of its function, so it may be shared with a UART receiver.
+pub struct PioManchesterSwo<P: PIOExt, SM: StateMachineIndex> {
+    state: Option<State<P, SM>>,
+    rx: Rx<(P, SM)>,
+    offset: u8,
+    decoder: ManchesterDecoder,
+    // 次に受け取るパルスのレベル
+    level: bool,
+}
+
+impl<P: PIOExt, SM: StateMachineIndex> PioManchesterSwo<P, SM> {
+    pub fn new(pio: &mut PIO<P>, sm: UninitStateMachine<(P, SM)>, swo_pin: u8, system_clock_hz: u32) -> Self {
+        // Low, Highのパルスの幅を交互にRX FIFOに書き込む。幅は2サイクル単位
+        let program = pio_proc::pio_asm!(
+            "    wait 0 pin 0",             // Lowになるまで待って同期する
+            ".wrap_target",
+            "    mov x, ~null",
+            "low_loop:",
+            "    jmp pin low_end",          // Highになったら終わり
+            "    jmp x-- low_loop",
+            "low_end:",
+            "    mov isr, ~x",              // Lowの幅
+            "    push",
+            "    mov x, ~null",
+            "high_loop:",
+            "    jmp pin high_next",
+            "    jmp high_end",             // Lowになったら終わり
+            "high_next:",
+            "    jmp x-- high_loop",
+            "high_end:",
+            "    mov isr, ~x",              // Highの幅
+            "    push",
+            ".wrap",
+        );
+        let installed = pio.install(&program.program).unwrap();
+        let offset = installed.offset();
+        let (sm, rx, _tx) = PIOBuilder::from_program(installed)
+            .in_pin_base(swo_pin)
+            .jmp_pin(swo_pin)
+            .clock_divisor_fixed_point(1, 0)
+            .build(sm);
+        Self {
+            state: Some(State::Stopped(sm)),
+            rx,
+            offset,
+            decoder: ManchesterDecoder::new(system_clock_hz / PIO_CYCLES_PER_TICK),
+            level: false,
+        }
+    }
+}
+
+impl<P: PIOExt, SM: StateMachineIndex> SwoReceiver for PioManchesterSwo<P, SM> {
+    fn set_mode(&mut self, mode: SwoMode) -> bool {
+        // UARTモードには対応しない
+        mode != SwoMode::Uart
+    }
+
+    fn set_baudrate(&mut self, baudrate: u32) -> u32 {
+        // ボーレートは受信したパケットから検出する。まだ受信していなければ指定されたボーレートで受信できるかを返す
+        let detected = self.decoder.baudrate();
+        if detected != 0 {
+            return detected;
+        }
+        let max_baudrate = self.decoder.tick_hz() / (2 * MANCHESTER_MIN_HALF_BIT_TICKS);
+        if baudrate <= max_baudrate {
+            baudrate
+        } else {
+            0
+        }
+    }
+
+    fn start(&mut self) {
+        self.state = match self.state.take() {
+            Some(State::Stopped(mut sm)) => {
+                // 前回の捕捉で残っているパルスを捨てて、プログラムの先頭から始める
+                // ISRはmovで上書きするので、前回の途中の値が残っていても構わない
+                while self.rx.read().is_some() {}
+                sm.exec_instruction(Instruction {
+                    operands: InstructionOperands::JMP {
+                        condition: JmpCondition::Always,
+                        address: self.offset,
+                    },
+                    delay: 0,
+                    side_set: None,
+                });
+                self.decoder.reset();
+                self.level = false;
+                Some(State::Running(sm.start()))
+            }
+            state => state,
+        };
+    }
+
+    fn stop(&mut self) {
+        self.state = match self.state.take() {
+            Some(State::Running(sm)) => Some(State::Stopped(sm.stop())),
+            state => state,
+        };
+    }
+
+    fn read(&mut self) -> nb::Result<u8, ()> {
+        while let Some(ticks) = self.rx.read() {
+            let level = self.level;
+            self.level = !level;
+            if let Some(data) = self.decoder.pulse(level, ticks.saturating_add(PULSE_OVERHEAD_TICKS)) {
+                return Ok(data);
+            }
+        }
+        Err(nb::Error::WouldBlock)
+    }
+}
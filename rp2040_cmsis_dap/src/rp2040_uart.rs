@@ -0,0 +1,88 @@
+// Copyright 2023 Kenta Ida
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use fugit::HertzU32;
+use rp_pico::hal::uart::{Disabled, Enabled, UartConfig, UartDevice, UartPeripheral, ValidUartPinout};
+
+enum State<D: UartDevice, P: ValidUartPinout<D>> {
+    Disabled(UartPeripheral<Disabled, D, P>),
+    Enabled(UartPeripheral<Enabled, D, P>),
+}
+
+/// UART peripheral which is enabled and disabled in place.
+pub struct Rp2040Uart<D: UartDevice, P: ValidUartPinout<D>> {
+    state: Option<State<D, P>>,
+    peripheral_clock_hz: u32,
+}
+
+impl<D: UartDevice, P: ValidUartPinout<D>> Rp2040Uart<D, P> {
+    pub fn new(uart: UartPeripheral<Disabled, D, P>, peripheral_clock_hz: u32) -> Self {
+        Self {
+            state: Some(State::Disabled(uart)),
+            peripheral_clock_hz,
+        }
+    }
+
+    /// Returns the baudrate the UART actually generates for `baudrate`, or 0 if out of range.
+    pub fn actual_baudrate(&self, baudrate: u32) -> u32 {
+        if baudrate == 0 {
+            return 0;
+        }
+        // PL011のボーレート分周比 (整数部16ビット、小数部6ビット) を求める
+        let divisor = (8 * self.peripheral_clock_hz as u64) / baudrate as u64;
+        let int = divisor >> 7;
+        let frac = (divisor & 0x7f).div_ceil(2);
+        if int == 0 || int >= 0xffff {
+            return 0;
+        }
+        ((4 * self.peripheral_clock_hz as u64) / (64 * int + frac)) as u32
+    }
+
+    /// Enables the UART with `config`, disabling it first if it is enabled.
+    ///
+    /// Returns the actual baudrate, or 0 without enabling the UART if the baudrate is out of range.
+    pub fn enable(&mut self, config: UartConfig) -> u32 {
+        // enableは範囲外の分周比を黙って丸めてしまうので、先に確認して断る
+        let baudrate = self.actual_baudrate(config.baudrate.to_Hz());
+        if baudrate == 0 {
+            return 0;
+        }
+        self.disable();
+        self.state = match self.state.take() {
+            // ボーレートが0の時だけ失敗するが、確認済みなので失敗しない
+            Some(State::Disabled(uart)) => {
+                uart.enable(config, HertzU32::Hz(self.peripheral_clock_hz)).ok().map(State::Enabled)
+            }
+            state => state,
+        };
+        baudrate
+    }
+
+    pub fn disable(&mut self) {
+        self.state = match self.state.take() {
+            Some(State::Enabled(uart)) => Some(State::Disabled(uart.disable())),
+            state => state,
+        };
+    }
+
+    /// Returns the UART if it is enabled.
+    pub fn enabled(&mut self) -> Option<&mut UartPeripheral<Enabled, D, P>> {
+        match self.state.as_mut() {
+            Some(State::Enabled(uart)) => Some(uart),
+            _ => None,
+        }
+    }
+}
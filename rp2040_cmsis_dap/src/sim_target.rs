@@ -0,0 +1,881 @@
+// Copyright 2023 Kenta Ida
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::dap_processor::DapError;
+use crate::jtagio::*;
+use crate::swdio::*;
+
+const CTRL_STAT_STICKY_FLAGS: u32 =
+    CTRL_STAT_STICKYORUN | CTRL_STAT_STICKYCMP | CTRL_STAT_STICKYERR | CTRL_STAT_WDATAERR;
+
+// JTAG-DPのIRは4ビットで、Capture-IRでは0b0001を読み込む
+const JTAG_IR_LENGTH: usize = 4;
+const JTAG_IR_CAPTURE: u64 = 0b0001;
+// DPACC/APACCスキャンチェーンの長さ (ACKまたはRnW, A[3:2]の3ビットとデータ32ビット)
+const JTAG_ACC_LENGTH: usize = 35;
+// DPACC/APACCのACK (OK/FAULT, WAIT)
+const JTAG_ACK_OK: u64 = 0b010;
+const JTAG_ACK_WAIT: u64 = 0b001;
+
+/// States of the JTAG TAP controller.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TapState {
+    TestLogicReset,
+    RunTestIdle,
+    SelectDrScan,
+    CaptureDr,
+    ShiftDr,
+    Exit1Dr,
+    PauseDr,
+    Exit2Dr,
+    UpdateDr,
+    SelectIrScan,
+    CaptureIr,
+    ShiftIr,
+    Exit1Ir,
+    PauseIr,
+    Exit2Ir,
+    UpdateIr,
+}
+
+impl TapState {
+    /// Returns the state after a TCK cycle with `tms`.
+    fn next(self, tms: bool) -> Self {
+        use TapState::*;
+        match (self, tms) {
+            (TestLogicReset, true) | (SelectIrScan, true) => TestLogicReset,
+            (TestLogicReset, false) | (RunTestIdle, false) | (UpdateDr, false) | (UpdateIr, false) => RunTestIdle,
+            (RunTestIdle, true) | (UpdateDr, true) | (UpdateIr, true) => SelectDrScan,
+            (SelectDrScan, false) => CaptureDr,
+            (CaptureDr, false) | (ShiftDr, false) | (Exit2Dr, false) => ShiftDr,
+            (CaptureDr, true) | (ShiftDr, true) => Exit1Dr,
+            (Exit1Dr, false) | (PauseDr, false) => PauseDr,
+            (PauseDr, true) => Exit2Dr,
+            (Exit1Dr, true) | (Exit2Dr, true) => UpdateDr,
+            (SelectDrScan, true) => SelectIrScan,
+            (SelectIrScan, false) => CaptureIr,
+            (CaptureIr, false) | (ShiftIr, false) | (Exit2Ir, false) => ShiftIr,
+            (CaptureIr, true) | (ShiftIr, true) => Exit1Ir,
+            (Exit1Ir, false) | (PauseIr, false) => PauseIr,
+            (PauseIr, true) => Exit2Ir,
+            (Exit1Ir, true) | (Exit2Ir, true) => UpdateIr,
+        }
+    }
+}
+
+/// Software model of an ARM ADIv5 SWD/JTAG target.
+///
+/// Implements a DP (DPIDR, ABORT, CTRL/STAT, SELECT, RESEND, RDBUFF) and a MEM-AP at APSEL 0
+/// (CSW, TAR, DRW, BD0-BD3, IDR) backed by `N` bytes of RAM at `memory_base`.
+/// On JTAG the DP is accessed through a JTAG-DP TAP with a 4 bit IR (IDCODE, DPACC, APACC, ABORT and BYPASS).
+/// WAIT/FAULT responses and parity errors can be injected to exercise the error paths of the probe.
+pub struct SimulatedTarget<const N: usize> {
+    pub dpidr: u32,
+    pub ap_idr: u32,
+    connected: bool,
+    clock_hz: u32,
+    ctrl_stat: u32,
+    select: u32,
+    rdbuff: u32,
+    csw: u32,
+    tar: u32,
+    memory_base: u32,
+    memory: [u8; N],
+    wait_count: u32,
+    fault_count: u32,
+    parity_error_count: u32,
+    transfer_count: u32,
+    swj_pins: u8,
+    reset_count: u32,
+    elapsed_us: u64,
+    tap_state: TapState,
+    ir: u32,
+    // JTAGのIRまたはDRのシフト・レジスタ。LSBがTDOに出る
+    shift_register: u64,
+    shift_length: usize,
+    // 前回のDPACC/APACCの結果。次のCapture-DRで読み込む
+    jtag_result: u32,
+    // WAITを返したスキャンはUpdate-DRで何もしない
+    jtag_wait: bool,
+}
+
+impl<const N: usize> SimulatedTarget<N> {
+    pub fn new(memory_base: u32) -> Self {
+        Self {
+            dpidr: 0x2ba0_1477,
+            ap_idr: 0x2477_0011,
+            connected: false,
+            clock_hz: 0,
+            ctrl_stat: 0,
+            select: 0,
+            rdbuff: 0,
+            csw: CSW_SIZE_32,
+            tar: 0,
+            memory_base,
+            memory: [0u8; N],
+            wait_count: 0,
+            fault_count: 0,
+            parity_error_count: 0,
+            transfer_count: 0,
+            swj_pins: SWJ_PIN_SWCLK_TCK | SWJ_PIN_SWDIO_TMS | SWJ_PIN_NRESET,
+            reset_count: 0,
+            elapsed_us: 0,
+            tap_state: TapState::TestLogicReset,
+            ir: JTAG_IDCODE,
+            shift_register: 0,
+            shift_length: 0,
+            jtag_result: 0,
+            jtag_wait: false,
+        }
+    }
+
+    pub fn memory(&self) -> &[u8; N] {
+        &self.memory
+    }
+
+    pub fn memory_mut(&mut self) -> &mut [u8; N] {
+        &mut self.memory
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    pub fn clock_hz(&self) -> u32 {
+        self.clock_hz
+    }
+
+    pub fn ctrl_stat(&self) -> u32 {
+        self.ctrl_stat
+    }
+
+    /// Number of SWD transfers the target has received, including the ones answered with WAIT/FAULT.
+    pub fn transfer_count(&self) -> u32 {
+        self.transfer_count
+    }
+
+    pub fn is_reset_asserted(&self) -> bool {
+        self.swj_pins & SWJ_PIN_NRESET == 0
+    }
+
+    /// Number of times the target has been reset by nRESET or AIRCR.SYSRESETREQ.
+    pub fn reset_count(&self) -> u32 {
+        self.reset_count
+    }
+
+    /// Total time the probe has waited by `SwdIo::delay_us`.
+    pub fn elapsed_us(&self) -> u64 {
+        self.elapsed_us
+    }
+
+    /// Responds with WAIT to the next `count` transfers.
+    pub fn inject_wait(&mut self, count: u32) {
+        self.wait_count = count;
+    }
+
+    /// Responds with FAULT to the next `count` transfers.
+    pub fn inject_fault(&mut self, count: u32) {
+        self.fault_count = count;
+    }
+
+    /// Corrupts the parity of the next `count` data phases.
+    pub fn inject_parity_error(&mut self, count: u32) {
+        self.parity_error_count = count;
+    }
+
+    fn is_sticky(&self) -> bool {
+        self.ctrl_stat & CTRL_STAT_STICKY_FLAGS != 0
+    }
+
+    fn read_dp(&mut self, address: u8) -> u32 {
+        match address {
+            DP_IDCODE => self.dpidr,
+            DP_CTRL_STAT if self.select & 0x0f == 0 => self.ctrl_stat,
+            DP_RESEND | DP_RDBUFF => self.rdbuff,
+            _ => 0,
+        }
+    }
+
+    fn write_dp(&mut self, address: u8, data: u32) {
+        match address {
+            DP_ABORT => {
+                if data & ABORT_STKCMPCLR != 0 {
+                    self.ctrl_stat &= !CTRL_STAT_STICKYCMP;
+                }
+                if data & ABORT_STKERRCLR != 0 {
+                    self.ctrl_stat &= !CTRL_STAT_STICKYERR;
+                }
+                if data & ABORT_WDERRCLR != 0 {
+                    self.ctrl_stat &= !CTRL_STAT_WDATAERR;
+                }
+                if data & ABORT_ORUNERRCLR != 0 {
+                    self.ctrl_stat &= !CTRL_STAT_STICKYORUN;
+                }
+            }
+            DP_CTRL_STAT if self.select & 0x0f == 0 => {
+                // 電源要求はそのまま応答に反映する。Stickyフラグは書き込みでは変化しない
+                let mut ctrl_stat = (self.ctrl_stat & CTRL_STAT_STICKY_FLAGS)
+                    | (data & !CTRL_STAT_STICKY_FLAGS & !(CTRL_STAT_CDBGPWRUPACK | CTRL_STAT_CSYSPWRUPACK));
+                if data & CTRL_STAT_CDBGPWRUPREQ != 0 {
+                    ctrl_stat |= CTRL_STAT_CDBGPWRUPACK;
+                }
+                if data & CTRL_STAT_CSYSPWRUPREQ != 0 {
+                    ctrl_stat |= CTRL_STAT_CSYSPWRUPACK;
+                }
+                self.ctrl_stat = ctrl_stat;
+            }
+            DP_SELECT => self.select = data,
+            _ => {}
+        }
+    }
+
+    fn ap_address(&self, address: u8) -> Option<u8> {
+        // MEM-APはAPSEL = 0のみ
+        if self.select >> 24 != 0 {
+            return None;
+        }
+        Some(((self.select & 0xf0) as u8) | address)
+    }
+
+    fn access_size(&self) -> u32 {
+        match self.csw & CSW_SIZE_MASK {
+            CSW_SIZE_8 => 1,
+            CSW_SIZE_16 => 2,
+            _ => 4,
+        }
+    }
+
+    fn increment_tar(&mut self) {
+        if self.csw & CSW_ADDRINC_MASK == CSW_ADDRINC_SINGLE {
+            // TARの自動インクリメントは1KB境界で折り返す
+            let tar = self.tar.wrapping_add(self.access_size());
+            self.tar = (self.tar & !0x3ff) | (tar & 0x3ff);
+        }
+    }
+
+    fn memory_range(&self, address: u32, size: u32) -> Option<core::ops::Range<usize>> {
+        let offset = address.checked_sub(self.memory_base)? as usize;
+        let end = offset.checked_add(size as usize)?;
+        if end <= N {
+            Some(offset..end)
+        } else {
+            None
+        }
+    }
+
+    fn read_memory(&mut self, address: u32, size: u32) -> u32 {
+        let address = address & !(size - 1);
+        match self.memory_range(address, size) {
+            Some(range) => {
+                // 読み出したデータはアドレスに対応するバイトレーンに配置する
+                let value = self.memory[range]
+                    .iter()
+                    .rev()
+                    .fold(0u32, |value, byte| (value << 8) | *byte as u32);
+                value << ((address & 3) * 8)
+            }
+            None => {
+                self.ctrl_stat |= CTRL_STAT_STICKYERR;
+                0
+            }
+        }
+    }
+
+    fn write_memory(&mut self, address: u32, size: u32, data: u32) {
+        let address = address & !(size - 1);
+        if address == AIRCR && size == 4 {
+            // VECTKEYが一致した場合のみSYSRESETREQを受け付ける
+            if data & 0xffff_0000 == AIRCR_VECTKEY && data & AIRCR_SYSRESETREQ != 0 {
+                self.reset_count += 1;
+            }
+            return;
+        }
+        match self.memory_range(address, size) {
+            Some(range) => {
+                let data = data >> ((address & 3) * 8);
+                for (index, byte) in self.memory[range].iter_mut().enumerate() {
+                    *byte = (data >> (index * 8)) as u8;
+                }
+            }
+            None => self.ctrl_stat |= CTRL_STAT_STICKYERR,
+        }
+    }
+
+    fn read_ap(&mut self, address: u8) -> u32 {
+        match self.ap_address(address) {
+            Some(AP_CSW) => self.csw | CSW_DEVICEEN,
+            Some(AP_TAR) => self.tar,
+            Some(AP_DRW) => {
+                let value = self.read_memory(self.tar, self.access_size());
+                self.increment_tar();
+                value
+            }
+            Some(address @ AP_BD0..=AP_BD3) => {
+                self.read_memory((self.tar & !0x0f) | (address & 0x0c) as u32, 4)
+            }
+            Some(AP_IDR) => self.ap_idr,
+            _ => 0,
+        }
+    }
+
+    fn write_ap(&mut self, address: u8, data: u32) {
+        match self.ap_address(address) {
+            Some(AP_CSW) => self.csw = data & !CSW_DEVICEEN,
+            Some(AP_TAR) => self.tar = data,
+            Some(AP_DRW) => {
+                self.write_memory(self.tar, self.access_size(), data);
+                self.increment_tar();
+            }
+            Some(address @ AP_BD0..=AP_BD3) => {
+                self.write_memory((self.tar & !0x0f) | (address & 0x0c) as u32, 4, data)
+            }
+            _ => {}
+        }
+    }
+
+    fn capture_dr(&mut self) {
+        self.jtag_wait = false;
+        let (value, length) = match self.ir {
+            JTAG_IDCODE => (self.dpidr as u64, 32),
+            JTAG_DPACC | JTAG_APACC if self.wait_count > 0 => {
+                // WAITはDAP_Transferと同じく転送として数える
+                self.transfer_count += 1;
+                self.wait_count -= 1;
+                self.jtag_wait = true;
+                (JTAG_ACK_WAIT, JTAG_ACC_LENGTH)
+            }
+            JTAG_DPACC | JTAG_APACC | JTAG_ABORT => {
+                (((self.jtag_result as u64) << 3) | JTAG_ACK_OK, JTAG_ACC_LENGTH)
+            }
+            // BYPASS
+            _ => (0, 1),
+        };
+        self.shift_register = value;
+        self.shift_length = length;
+    }
+
+    fn update_dr(&mut self, config: &SwdIoConfig) {
+        let data = (self.shift_register >> 3) as u32;
+        // RnW, A[3:2]をDAP_Transferのリクエストの形に直す
+        let request = ((self.shift_register & 0x07) << 1) as u8;
+        match self.ir {
+            JTAG_ABORT => self.write_dp(DP_ABORT, data),
+            JTAG_DPACC | JTAG_APACC if !self.jtag_wait => {
+                let is_ap = self.ir == JTAG_APACC;
+                let request = if is_ap { request | DAP_TRANSFER_APNDP } else { request };
+                // JTAGではすべてのリードがポステッドで、結果は次のスキャンで読み出す
+                // FAULTはACKには現れないので、結果を0とする
+                self.jtag_result = match self.swd_transfer(config, request, data) {
+                    Ok(_) if is_ap && request & DAP_TRANSFER_RNW != 0 => self.rdbuff,
+                    Ok(value) => value,
+                    Err(_) => 0,
+                };
+            }
+            _ => {}
+        }
+    }
+}
+
+impl<const N: usize> SwdIo for SimulatedTarget<N> {
+    fn connect(&mut self) {
+        self.connected = true;
+    }
+
+    fn disconnect(&mut self) {
+        self.connected = false;
+    }
+
+    fn swj_clock(
+        &mut self,
+        config: &mut SwdIoConfig,
+        frequency_hz: u32,
+    ) -> core::result::Result<(), DapError> {
+        let _ = config;
+        if frequency_hz == 0 {
+            return Err(DapError::InvalidCommand);
+        }
+        self.clock_hz = frequency_hz;
+        Ok(())
+    }
+
+    fn swj_sequence(&mut self, config: &SwdIoConfig, count: usize, data: &[u8]) {
+        let _ = (config, count, data);
+    }
+
+    fn swd_read_sequence(&mut self, config: &SwdIoConfig, count: usize, data: &mut [u8]) {
+        let _ = config;
+        // SWDIOはプルアップされている
+        data[..count.div_ceil(8)].fill(0xff);
+    }
+
+    fn swd_write_sequence(&mut self, config: &SwdIoConfig, count: usize, data: &[u8]) {
+        let _ = (config, count, data);
+    }
+
+    fn swd_transfer(
+        &mut self,
+        config: &SwdIoConfig,
+        request: SwdRequest,
+        data: u32,
+    ) -> core::result::Result<u32, DapError> {
+        let _ = config;
+        if !self.connected {
+            return Err(DapError::SwdError(DAP_TRANSFER_NO_ACK));
+        }
+        self.transfer_count += 1;
+        if self.wait_count > 0 {
+            self.wait_count -= 1;
+            return Err(DapError::SwdError(DAP_TRANSFER_WAIT));
+        }
+        if self.fault_count > 0 {
+            self.fault_count -= 1;
+            return Err(DapError::SwdError(DAP_TRANSFER_FAULT));
+        }
+
+        let is_ap = request & DAP_TRANSFER_APNDP != 0;
+        let is_read = request & DAP_TRANSFER_RNW != 0;
+        let address = request & (DAP_TRANSFER_A2 | DAP_TRANSFER_A3);
+        // Stickyフラグが立っている間は DPIDR, CTRL/STATの読み出しとABORTへの書き込み以外はFAULTになる
+        let always_accepted = !is_ap
+            && ((is_read && (address == DP_IDCODE || address == DP_CTRL_STAT)) || (!is_read && address == DP_ABORT));
+        if self.is_sticky() && !always_accepted {
+            return Err(DapError::SwdError(DAP_TRANSFER_FAULT));
+        }
+
+        let parity_error = self.parity_error_count > 0;
+        if parity_error {
+            self.parity_error_count -= 1;
+        }
+        if is_read {
+            let value = if is_ap {
+                // APの読み出しはポステッド。前回の読み出し結果を返す
+                let value = self.rdbuff;
+                self.rdbuff = self.read_ap(address);
+                value
+            } else {
+                self.read_dp(address)
+            };
+            if parity_error {
+                return Err(DapError::SwdError(DAP_TRANSFER_ERROR));
+            }
+            Ok(value)
+        } else {
+            if parity_error {
+                // 書き込みデータのパリティエラー。書き込みは行わない
+                self.ctrl_stat |= CTRL_STAT_WDATAERR;
+            } else if is_ap {
+                self.write_ap(address, data);
+            } else {
+                self.write_dp(address, data);
+            }
+            Ok(data)
+        }
+    }
+
+    fn enable_output(&mut self) {}
+
+    fn disable_output(&mut self) {}
+
+    fn set_swj_pins(&mut self, output: u8, select: u8) {
+        let pins = (self.swj_pins & !select) | (output & select);
+        if self.swj_pins & SWJ_PIN_NRESET != 0 && pins & SWJ_PIN_NRESET == 0 {
+            self.reset_count += 1;
+        }
+        self.swj_pins = pins;
+    }
+
+    fn get_swj_pins(&mut self) -> u8 {
+        self.swj_pins
+    }
+
+    fn delay_us(&mut self, us: u32) {
+        self.elapsed_us += us as u64;
+    }
+
+    fn jtag(&mut self) -> Option<&mut dyn JtagIo> {
+        Some(self)
+    }
+}
+
+impl<const N: usize> JtagIo for SimulatedTarget<N> {
+    fn jtag_connect(&mut self) {
+        self.connected = true;
+    }
+
+    fn jtag_disconnect(&mut self) {
+        self.connected = false;
+    }
+
+    fn jtag_sequence(&mut self, config: &SwdIoConfig, count: usize, tms: bool, tdi: u64) -> u64 {
+        let mut tdo = 0;
+        for bit in 0..count {
+            match self.tap_state {
+                TapState::CaptureDr => self.capture_dr(),
+                TapState::CaptureIr => {
+                    self.shift_register = JTAG_IR_CAPTURE;
+                    self.shift_length = JTAG_IR_LENGTH;
+                }
+                TapState::ShiftDr | TapState::ShiftIr => {
+                    tdo |= (self.shift_register & 1) << bit;
+                    let tdi = (tdi >> bit) & 1;
+                    self.shift_register = (self.shift_register >> 1) | (tdi << (self.shift_length - 1));
+                }
+                _ => {}
+            }
+            self.tap_state = self.tap_state.next(tms);
+            match self.tap_state {
+                TapState::TestLogicReset => self.ir = JTAG_IDCODE,
+                TapState::UpdateIr => self.ir = self.shift_register as u32,
+                TapState::UpdateDr => self.update_dr(config),
+                _ => {}
+            }
+        }
+        tdo
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dap_processor::DapProcessor;
+
+    const MEMORY_BASE: u32 = 0x2000_0000;
+
+    // DAP_Transfer requests
+    const READ_DPIDR: u8 = DAP_TRANSFER_RNW | DP_IDCODE;
+    const READ_CTRL_STAT: u8 = DAP_TRANSFER_RNW | DP_CTRL_STAT;
+    const READ_RDBUFF: u8 = DAP_TRANSFER_RNW | DP_RDBUFF;
+    const WRITE_ABORT: u8 = DP_ABORT;
+    const WRITE_CSW: u8 = DAP_TRANSFER_APNDP | AP_CSW;
+    const WRITE_TAR: u8 = DAP_TRANSFER_APNDP | AP_TAR;
+    const WRITE_DRW: u8 = DAP_TRANSFER_APNDP | AP_DRW;
+    const READ_DRW: u8 = DAP_TRANSFER_APNDP | DAP_TRANSFER_RNW | AP_DRW;
+
+    fn process(processor: &mut DapProcessor<SimulatedTarget<256>>, request: &[u8]) -> Vec<u8> {
+        let mut response = [0u8; 64];
+        let length = processor.process(request, &mut response);
+        response[..length].to_vec()
+    }
+
+    /// Returns a processor connected to the target by SWD, with the MEM-AP set to 32bit auto increment access.
+    fn connect() -> DapProcessor<SimulatedTarget<256>> {
+        let mut processor = DapProcessor::new(SimulatedTarget::new(MEMORY_BASE));
+        assert_eq!(process(&mut processor, &[0x02, 0x01]), [0x02, 0x01]);
+        let mut request = vec![0x05, 0x00, 1, WRITE_CSW];
+        request.extend((CSW_SIZE_32 | CSW_ADDRINC_SINGLE).to_le_bytes());
+        assert_eq!(process(&mut processor, &request), [0x05, 1, DAP_TRANSFER_OK]);
+        processor
+    }
+
+    /// Returns a processor connected to the target by JTAG, with the TAP in Run-Test/Idle.
+    fn connect_jtag() -> DapProcessor<SimulatedTarget<256>> {
+        let mut processor = DapProcessor::new(SimulatedTarget::new(MEMORY_BASE));
+        assert_eq!(process(&mut processor, &[0x02, 0x02]), [0x02, 0x02]);
+        // TMS = 1を5クロックでTest-Logic-Reset、TMS = 0を1クロックでRun-Test/Idle
+        assert_eq!(process(&mut processor, &[0x14, 2, 0x45, 0x00, 0x01, 0x00]), [0x14, 0x00]);
+        processor
+    }
+
+    /// Builds a DAP_Transfer request from (request, data) pairs. Data is ignored for reads without match value.
+    fn transfer(transfers: &[(u8, u32)]) -> Vec<u8> {
+        let mut request = vec![0x05, 0x00, transfers.len() as u8];
+        for (transfer_request, data) in transfers {
+            request.push(*transfer_request);
+            if transfer_request & DAP_TRANSFER_RNW == 0 || transfer_request & DAP_TRANSFER_MATCH_VALUE != 0 {
+                request.extend(data.to_le_bytes());
+            }
+        }
+        request
+    }
+
+    fn configure_transfer(processor: &mut DapProcessor<SimulatedTarget<256>>, wait_retry: u16, match_retry: u16) {
+        let mut request = vec![0x04, 0];
+        request.extend(wait_retry.to_le_bytes());
+        request.extend(match_retry.to_le_bytes());
+        assert_eq!(process(processor, &request), [0x04, 0x00]);
+    }
+
+    #[test]
+    fn transfer_read_and_write() {
+        let mut processor = connect();
+        let response = process(
+            &mut processor,
+            &transfer(&[(WRITE_TAR, MEMORY_BASE + 0x10), (WRITE_DRW, 0x1234_5678), (READ_DPIDR, 0)]),
+        );
+        assert_eq!(response, [0x05, 3, DAP_TRANSFER_OK, 0x77, 0x14, 0xa0, 0x2b]);
+        assert_eq!(processor.swdio().memory()[0x10..0x14], [0x78, 0x56, 0x34, 0x12]);
+
+        let response = process(&mut processor, &transfer(&[(WRITE_TAR, MEMORY_BASE + 0x10), (READ_DRW, 0)]));
+        assert_eq!(response, [0x05, 2, DAP_TRANSFER_OK, 0x78, 0x56, 0x34, 0x12]);
+    }
+
+    #[test]
+    fn posted_read_is_completed_by_rdbuff() {
+        let mut processor = connect();
+        processor.swdio().memory_mut()[..8].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8]);
+        let transfer_count = processor.swdio().transfer_count();
+        let response = process(
+            &mut processor,
+            &transfer(&[(WRITE_TAR, MEMORY_BASE), (READ_DRW, 0), (READ_DRW, 0)]),
+        );
+        assert_eq!(response, [0x05, 3, DAP_TRANSFER_OK, 1, 2, 3, 4, 5, 6, 7, 8]);
+        // TAR, DRW, DRW, RDBUFF
+        assert_eq!(processor.swdio().transfer_count() - transfer_count, 4);
+
+        // DRWの後にRDBUFFを読むと、ポステッドの値とRDBUFFの値の両方が返る
+        let response = process(&mut processor, &transfer(&[(WRITE_TAR, MEMORY_BASE), (READ_DRW, 0), (READ_RDBUFF, 0)]));
+        assert_eq!(response, [0x05, 3, DAP_TRANSFER_OK, 1, 2, 3, 4, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn wait_is_retried() {
+        let mut processor = connect();
+        configure_transfer(&mut processor, 3, 0);
+        processor.swdio().inject_wait(3);
+        let response = process(&mut processor, &transfer(&[(READ_DPIDR, 0)]));
+        assert_eq!(response, [0x05, 1, DAP_TRANSFER_OK, 0x77, 0x14, 0xa0, 0x2b]);
+    }
+
+    #[test]
+    fn wait_retry_runs_out() {
+        let mut processor = connect();
+        configure_transfer(&mut processor, 3, 0);
+        processor.swdio().inject_wait(10);
+        let transfer_count = processor.swdio().transfer_count();
+        let response = process(&mut processor, &transfer(&[(READ_DPIDR, 0), (READ_DPIDR, 0)]));
+        assert_eq!(response, [0x05, 0, DAP_TRANSFER_WAIT]);
+        // 最初の試行と3回の再試行
+        assert_eq!(processor.swdio().transfer_count() - transfer_count, 4);
+    }
+
+    #[test]
+    fn fault_until_sticky_error_is_cleared() {
+        let mut processor = connect();
+        // 範囲外への書き込みでSTICKYERRが立ち、以降のAPアクセスはFAULTになる
+        let response = process(
+            &mut processor,
+            &transfer(&[(WRITE_TAR, 0x1000_0000), (WRITE_DRW, 0), (READ_DRW, 0)]),
+        );
+        assert_eq!(response, [0x05, 2, DAP_TRANSFER_FAULT]);
+
+        let response = process(&mut processor, &transfer(&[(READ_CTRL_STAT, 0)]));
+        assert_eq!(response[..3], [0x05, 1, DAP_TRANSFER_OK]);
+        assert_ne!(read_u32(&response[3..]) & CTRL_STAT_STICKYERR, 0);
+
+        let response = process(
+            &mut processor,
+            &transfer(&[(WRITE_ABORT, ABORT_STKERRCLR), (WRITE_TAR, MEMORY_BASE), (READ_DRW, 0)]),
+        );
+        assert_eq!(response, [0x05, 3, DAP_TRANSFER_OK, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn parity_error() {
+        let mut processor = connect();
+        processor.swdio().inject_parity_error(1);
+        let response = process(&mut processor, &transfer(&[(READ_DPIDR, 0), (READ_DPIDR, 0)]));
+        assert_eq!(response, [0x05, 0, DAP_TRANSFER_ERROR]);
+        let response = process(&mut processor, &transfer(&[(READ_DPIDR, 0)]));
+        assert_eq!(response, [0x05, 1, DAP_TRANSFER_OK, 0x77, 0x14, 0xa0, 0x2b]);
+    }
+
+    #[test]
+    fn transfer_block() {
+        let mut processor = connect();
+        assert_eq!(
+            process(&mut processor, &transfer(&[(WRITE_TAR, MEMORY_BASE + 0x20)])),
+            [0x05, 1, DAP_TRANSFER_OK]
+        );
+        let mut request = vec![0x06, 0x00, 3, 0, WRITE_DRW];
+        for value in [0x1111_1111u32, 0x2222_2222, 0x3333_3333] {
+            request.extend(value.to_le_bytes());
+        }
+        assert_eq!(process(&mut processor, &request), [0x06, 3, 0, DAP_TRANSFER_OK]);
+
+        assert_eq!(
+            process(&mut processor, &transfer(&[(WRITE_TAR, MEMORY_BASE + 0x20)])),
+            [0x05, 1, DAP_TRANSFER_OK]
+        );
+        let mut expected = vec![0x06, 3, 0, DAP_TRANSFER_OK];
+        expected.extend(processor.swdio().memory()[0x20..0x2c].iter());
+        assert_eq!(process(&mut processor, &[0x06, 0x00, 3, 0, READ_DRW]), expected);
+        assert_eq!(expected[4..8], [0x11, 0x11, 0x11, 0x11]);
+    }
+
+    #[test]
+    fn transfer_block_read_is_truncated_to_the_response() {
+        let mut processor = connect();
+        assert_eq!(
+            process(&mut processor, &transfer(&[(WRITE_TAR, MEMORY_BASE)])),
+            [0x05, 1, DAP_TRANSFER_OK]
+        );
+        // 2ワード分の空きしかなければ2ワードだけ読み出す
+        let mut response = [0u8; 12];
+        assert_eq!(processor.process(&[0x06, 0x00, 3, 0, READ_DRW], &mut response), 12);
+        assert_eq!(response[..4], [0x06, 2, 0, DAP_TRANSFER_OK]);
+        // 1ワードも入らなければ何も転送していないので、転送数もレスポンスも0
+        let mut response = [0u8; 7];
+        assert_eq!(processor.process(&[0x06, 0x00, 3, 0, READ_DRW], &mut response), 4);
+        assert_eq!(response[..4], [0x06, 0, 0, 0]);
+    }
+
+    #[test]
+    fn transfer_block_without_transfers() {
+        let mut processor = connect();
+        let transfer_count = processor.swdio().transfer_count();
+        // 転送数0なら何も転送せず、転送数もレスポンスも0
+        assert_eq!(process(&mut processor, &[0x06, 0x00, 0, 0, READ_DRW]), [0x06, 0, 0, 0]);
+        assert_eq!(process(&mut processor, &[0x06, 0x00, 0, 0, WRITE_DRW]), [0x06, 0, 0, 0]);
+        assert_eq!(processor.swdio().transfer_count(), transfer_count);
+    }
+
+    #[test]
+    fn write_abort_clears_sticky_error() {
+        let mut processor = connect();
+        // 範囲外への書き込みでSTICKYERRを立てる
+        process(&mut processor, &transfer(&[(WRITE_TAR, 0x1000_0000), (WRITE_DRW, 0)]));
+        assert_ne!(processor.swdio().ctrl_stat() & CTRL_STAT_STICKYERR, 0);
+
+        let mut request = vec![0x08, 0];
+        request.extend(ABORT_STKERRCLR.to_le_bytes());
+        assert_eq!(process(&mut processor, &request), [0x08, 0x00]);
+        assert_eq!(processor.swdio().ctrl_stat() & CTRL_STAT_STICKYERR, 0);
+
+        // 接続していなければエラー
+        assert_eq!(process(&mut processor, &[0x03]), [0x03, 0x00]);
+        assert_eq!(process(&mut processor, &request), [0x08, 0xff]);
+    }
+
+    #[test]
+    fn jtag_sequence_captures_tdo() {
+        let mut processor = connect_jtag();
+        // Shift-IRに進み、Capture-IRで読み込んだ0b0001を読み出す。IRはIDCODEのまま戻る
+        let response = process(
+            &mut processor,
+            &[0x14, 4, 0x42, 0x00, 0x02, 0x00, 0x83, 0x06, 0xc1, 0x01],
+        );
+        assert_eq!(response, [0x14, 0x00, 0x01, 0x00]);
+        // Update-IR, Select-DR-Scan, Capture-DR, Shift-DRに進み、IDCODEを32ビット読み出す
+        let response = process(&mut processor, &[0x14, 3, 0x42, 0x00, 0x02, 0x00, 0xa0, 0, 0, 0, 0]);
+        assert_eq!(response, [0x14, 0x00, 0x77, 0x14, 0xa0, 0x2b]);
+    }
+
+    #[test]
+    fn jtag_configure() {
+        let mut processor = connect_jtag();
+        assert_eq!(process(&mut processor, &[0x15, 2, 4, 5]), [0x15, 0x00]);
+        assert_eq!(processor.jtag_config().count, 2);
+        assert_eq!(processor.jtag_config().ir_after[0], 5);
+        // デバイスがない、または多すぎる場合はエラーで、設定は変わらない
+        assert_eq!(process(&mut processor, &[0x15, 0]), [0x15, 0xff]);
+        assert_eq!(process(&mut processor, &[0x15, 9, 4, 4, 4, 4, 4, 4, 4, 4, 4]), [0x15, 0xff]);
+        assert_eq!(processor.jtag_config().count, 2);
+    }
+
+    #[test]
+    fn jtag_idcode() {
+        let mut processor = connect_jtag();
+        assert_eq!(process(&mut processor, &[0x16, 0]), [0x16, 0x00, 0x77, 0x14, 0xa0, 0x2b]);
+        // 存在しないデバイス
+        assert_eq!(process(&mut processor, &[0x16, 1]), [0x16, 0xff, 0, 0, 0, 0]);
+        // SWDで接続している間はエラー
+        assert_eq!(process(&mut processor, &[0x02, 0x01]), [0x02, 0x01]);
+        assert_eq!(process(&mut processor, &[0x16, 0]), [0x16, 0xff, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn jtag_transfer_read_and_write() {
+        let mut processor = connect_jtag();
+        let response = process(
+            &mut processor,
+            &transfer(&[
+                (WRITE_CSW, CSW_SIZE_32 | CSW_ADDRINC_SINGLE),
+                (WRITE_TAR, MEMORY_BASE + 0x10),
+                (WRITE_DRW, 0x1234_5678),
+                (READ_DPIDR, 0),
+            ]),
+        );
+        assert_eq!(response, [0x05, 4, DAP_TRANSFER_OK, 0x77, 0x14, 0xa0, 0x2b]);
+        assert_eq!(processor.swdio().memory()[0x10..0x14], [0x78, 0x56, 0x34, 0x12]);
+
+        // JTAGではすべてのリードがポステッド
+        processor.swdio().memory_mut()[0x14..0x18].copy_from_slice(&[1, 2, 3, 4]);
+        let response = process(
+            &mut processor,
+            &transfer(&[(WRITE_TAR, MEMORY_BASE + 0x10), (READ_DRW, 0), (READ_DRW, 0), (READ_DPIDR, 0)]),
+        );
+        assert_eq!(
+            response,
+            [0x05, 4, DAP_TRANSFER_OK, 0x78, 0x56, 0x34, 0x12, 1, 2, 3, 4, 0x77, 0x14, 0xa0, 0x2b]
+        );
+    }
+
+    #[test]
+    fn jtag_wait_is_retried() {
+        let mut processor = connect_jtag();
+        configure_transfer(&mut processor, 3, 0);
+        processor.swdio().inject_wait(3);
+        let response = process(&mut processor, &transfer(&[(READ_DPIDR, 0)]));
+        assert_eq!(response, [0x05, 1, DAP_TRANSFER_OK, 0x77, 0x14, 0xa0, 0x2b]);
+
+        processor.swdio().inject_wait(10);
+        let response = process(&mut processor, &transfer(&[(READ_DPIDR, 0)]));
+        assert_eq!(response, [0x05, 0, DAP_TRANSFER_WAIT]);
+    }
+
+    #[test]
+    fn jtag_write_abort_clears_sticky_error() {
+        let mut processor = connect_jtag();
+        process(&mut processor, &transfer(&[(WRITE_TAR, 0x1000_0000), (WRITE_DRW, 0)]));
+        assert_ne!(processor.swdio().ctrl_stat() & CTRL_STAT_STICKYERR, 0);
+
+        let mut request = vec![0x08, 0];
+        request.extend(ABORT_STKERRCLR.to_le_bytes());
+        assert_eq!(process(&mut processor, &request), [0x08, 0x00]);
+        assert_eq!(processor.swdio().ctrl_stat() & CTRL_STAT_STICKYERR, 0);
+        // 存在しないデバイス
+        request[1] = 1;
+        assert_eq!(process(&mut processor, &request), [0x08, 0xff]);
+    }
+
+    #[test]
+    fn match_value_with_mask() {
+        let mut processor = connect();
+        configure_transfer(&mut processor, 0, 2);
+        processor.swdio().memory_mut()[..4].copy_from_slice(&[0x01, 0x80, 0x00, 0x00]);
+        // 0x8000のビットだけを比較する
+        let response = process(
+            &mut processor,
+            &transfer(&[
+                (DAP_TRANSFER_MATCH_MASK, 0x0000_8000),
+                (WRITE_CSW, CSW_SIZE_32),
+                (WRITE_TAR, MEMORY_BASE),
+                (READ_DRW | DAP_TRANSFER_MATCH_VALUE, 0x0000_8000),
+            ]),
+        );
+        assert_eq!(response, [0x05, 4, DAP_TRANSFER_OK]);
+
+        let transfer_count = processor.swdio().transfer_count();
+        let response = process(&mut processor, &transfer(&[(READ_DRW | DAP_TRANSFER_MATCH_VALUE, 0)]));
+        assert_eq!(response, [0x05, 0, DAP_TRANSFER_OK | DAP_TRANSFER_MISMATCH]);
+        // リードの発行と、最初の読み出しと2回の再試行
+        assert_eq!(processor.swdio().transfer_count() - transfer_count, 4);
+    }
+
+    fn read_u32(buffer: &[u8]) -> u32 {
+        u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]])
+    }
+}
@@ -1,4 +1,5 @@
-use crate::cmsis_dap::DapError;
+use crate::dap_processor::DapError;
+use crate::jtagio::JtagIo;
 
 #[derive(Clone, Copy)]
 pub struct SwdIoConfig {
@@ -6,8 +7,98 @@ pub struct SwdIoConfig {
     pub idle_cycles: u32,
     pub turn_around_cycles: u32,
     pub always_generate_data_phase: bool,
+    pub wait_retry: u32,
+    pub match_retry: u32,
 }
 
+impl Default for SwdIoConfig {
+    fn default() -> Self {
+        Self {
+            clock_wait_cycles: 0,
+            idle_cycles: 0,
+            turn_around_cycles: 1,
+            always_generate_data_phase: false,
+            wait_retry: 100,
+            match_retry: 0,
+        }
+    }
+}
+
+// DAP_Transfer request bits
+pub const DAP_TRANSFER_APNDP: u8 = 1 << 0;
+pub const DAP_TRANSFER_RNW: u8 = 1 << 1;
+pub const DAP_TRANSFER_A2: u8 = 1 << 2;
+pub const DAP_TRANSFER_A3: u8 = 1 << 3;
+pub const DAP_TRANSFER_MATCH_VALUE: u8 = 1 << 4;
+pub const DAP_TRANSFER_MATCH_MASK: u8 = 1 << 5;
+
+// DAP_Transfer response bits
+pub const DAP_TRANSFER_OK: u8 = 1 << 0;
+pub const DAP_TRANSFER_WAIT: u8 = 1 << 1;
+pub const DAP_TRANSFER_FAULT: u8 = 1 << 2;
+pub const DAP_TRANSFER_ERROR: u8 = 1 << 3;
+pub const DAP_TRANSFER_MISMATCH: u8 = 1 << 4;
+pub const DAP_TRANSFER_NO_ACK: u8 = 0b111;
+
+// DAP_SWJ_Pins pin bits
+pub const SWJ_PIN_SWCLK_TCK: u8 = 1 << 0;
+pub const SWJ_PIN_SWDIO_TMS: u8 = 1 << 1;
+pub const SWJ_PIN_TDI: u8 = 1 << 2;
+pub const SWJ_PIN_TDO: u8 = 1 << 3;
+pub const SWJ_PIN_NTRST: u8 = 1 << 5;
+pub const SWJ_PIN_NRESET: u8 = 1 << 7;
+
+// DP register addresses (A3:A2)
+pub const DP_IDCODE: u8 = 0x00;
+pub const DP_ABORT: u8 = 0x00;
+pub const DP_CTRL_STAT: u8 = 0x04;
+pub const DP_SELECT: u8 = 0x08;
+pub const DP_RESEND: u8 = 0x08;
+pub const DP_RDBUFF: u8 = 0x0c;
+
+// ABORT register bits
+pub const ABORT_DAPABORT: u32 = 1 << 0;
+pub const ABORT_STKCMPCLR: u32 = 1 << 1;
+pub const ABORT_STKERRCLR: u32 = 1 << 2;
+pub const ABORT_WDERRCLR: u32 = 1 << 3;
+pub const ABORT_ORUNERRCLR: u32 = 1 << 4;
+
+// CTRL/STAT register bits
+pub const CTRL_STAT_STICKYORUN: u32 = 1 << 1;
+pub const CTRL_STAT_STICKYCMP: u32 = 1 << 4;
+pub const CTRL_STAT_STICKYERR: u32 = 1 << 5;
+pub const CTRL_STAT_WDATAERR: u32 = 1 << 7;
+pub const CTRL_STAT_CDBGPWRUPREQ: u32 = 1 << 28;
+pub const CTRL_STAT_CDBGPWRUPACK: u32 = 1 << 29;
+pub const CTRL_STAT_CSYSPWRUPREQ: u32 = 1 << 30;
+pub const CTRL_STAT_CSYSPWRUPACK: u32 = 1 << 31;
+
+// MEM-AP register addresses
+pub const AP_CSW: u8 = 0x00;
+pub const AP_TAR: u8 = 0x04;
+pub const AP_DRW: u8 = 0x0c;
+pub const AP_BD0: u8 = 0x10;
+pub const AP_BD3: u8 = 0x1c;
+pub const AP_IDR: u8 = 0xfc;
+
+// CSW register fields
+pub const CSW_SIZE_MASK: u32 = 0x07;
+pub const CSW_SIZE_8: u32 = 0x00;
+pub const CSW_SIZE_16: u32 = 0x01;
+pub const CSW_SIZE_32: u32 = 0x02;
+pub const CSW_ADDRINC_MASK: u32 = 0x30;
+pub const CSW_ADDRINC_SINGLE: u32 = 0x10;
+pub const CSW_DEVICEEN: u32 = 1 << 6;
+
+// Cortex-M AIRCR
+pub const AIRCR: u32 = 0xe000_ed0c;
+pub const AIRCR_VECTKEY: u32 = 0x05fa_0000;
+pub const AIRCR_SYSRESETREQ: u32 = 1 << 2;
+
+/// Lower 4 bits of the DAP_Transfer request byte (APnDP, RnW, A2, A3).
+///
+/// `SwdIo::swd_transfer` returns `DapError::SwdError(ack)` when the target does not respond with OK,
+/// where `ack` is the raw 3-bit ACK value, or `DAP_TRANSFER_ERROR` when a parity error is detected.
 pub type SwdRequest = u8;
 
 pub trait SwdIo {
@@ -29,4 +120,141 @@ pub trait SwdIo {
     ) -> core::result::Result<u32, DapError>;
     fn enable_output(&mut self);
     fn disable_output(&mut self);
-}
\ No newline at end of file
+    /// Drives the pins selected by `select` to the levels in `output`. Both are `SWJ_PIN_*` bit masks.
+    ///
+    /// Does nothing by default.
+    fn set_swj_pins(&mut self, output: u8, select: u8) {
+        let _ = (output, select);
+    }
+    /// Returns the levels of the pins as `SWJ_PIN_*` bit mask.
+    ///
+    /// Reports all pins low by default.
+    fn get_swj_pins(&mut self) -> u8 {
+        0
+    }
+    /// Waits for `us` microseconds. DAP_Delay and the pin wait of DAP_SWJ_Pins rely on it.
+    fn delay_us(&mut self, us: u32);
+    /// Returns the JTAG interface sharing the pins with this SWD interface, if supported.
+    fn jtag(&mut self) -> Option<&mut dyn JtagIo> {
+        None
+    }
+}
+
+/// Runs `transfer` again while it fails with WAIT, up to `wait_retry` times.
+pub fn retry_on_wait<F>(wait_retry: u32, mut transfer: F) -> core::result::Result<u32, DapError>
+where
+    F: FnMut() -> core::result::Result<u32, DapError>,
+{
+    let mut retry_count = 0;
+    loop {
+        match transfer() {
+            Err(DapError::SwdError(DAP_TRANSFER_WAIT)) if retry_count < wait_retry => {
+                retry_count += 1;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Bit level access to SWCLK/SWDIO, used to build `SwdIo` implementations on top of it.
+pub trait SwdBitIo {
+    /// Drives SWDIO and clocks out the lower `count` (up to 32) bits of `data`, LSB first.
+    fn write_bits(&mut self, config: &SwdIoConfig, count: usize, data: u32);
+    /// Releases SWDIO and clocks in `count` (up to 32) bits. The first bit is stored in LSB.
+    fn read_bits(&mut self, config: &SwdIoConfig, count: usize) -> u32;
+}
+
+/// Performs an SWD transfer (request, ACK and data phase) with `SwdBitIo`.
+pub fn swd_transfer_bits<T: SwdBitIo>(
+    io: &mut T,
+    config: &SwdIoConfig,
+    request: SwdRequest,
+    data: u32,
+) -> core::result::Result<u32, DapError> {
+    let request = request & (DAP_TRANSFER_APNDP | DAP_TRANSFER_RNW | DAP_TRANSFER_A2 | DAP_TRANSFER_A3);
+    let is_read = request & DAP_TRANSFER_RNW != 0;
+    let turn_around_cycles = config.turn_around_cycles as usize;
+    // Start, APnDP, RnW, A2, A3, Parity, Stop, Park
+    let parity = (request.count_ones() & 1) as u8;
+    let header = 0x81 | (request << 1) | (parity << 5);
+    io.write_bits(config, 8, header as u32);
+    // Turnaround + ACK
+    let ack = ((io.read_bits(config, turn_around_cycles + 3) >> turn_around_cycles) & 0x07) as u8;
+    match ack {
+        DAP_TRANSFER_OK => {
+            let result = if is_read {
+                // Data + Parity + Turnaround
+                let value = io.read_bits(config, 32);
+                let parity = io.read_bits(config, 1 + turn_around_cycles) & 1;
+                if parity == value.count_ones() & 1 {
+                    Ok(value)
+                } else {
+                    Err(DapError::SwdError(DAP_TRANSFER_ERROR))
+                }
+            } else {
+                // Turnaround + Data + Parity
+                io.read_bits(config, turn_around_cycles);
+                io.write_bits(config, 32, data);
+                io.write_bits(config, 1, data.count_ones() & 1);
+                Ok(data)
+            };
+            // Idle cycles
+            let mut idle_cycles = config.idle_cycles as usize;
+            while idle_cycles > 0 {
+                let count = idle_cycles.min(32);
+                io.write_bits(config, count, 0);
+                idle_cycles -= count;
+            }
+            result
+        }
+        DAP_TRANSFER_WAIT | DAP_TRANSFER_FAULT => {
+            if is_read {
+                if config.always_generate_data_phase {
+                    io.read_bits(config, 32);
+                    io.read_bits(config, 1);
+                }
+                io.read_bits(config, turn_around_cycles);
+            } else {
+                io.read_bits(config, turn_around_cycles);
+                if config.always_generate_data_phase {
+                    io.write_bits(config, 32, 0);
+                    io.write_bits(config, 1, 0);
+                }
+            }
+            Err(DapError::SwdError(ack))
+        }
+        _ => {
+            // Protocol error. Clock out the data phase to let the target release SWDIO.
+            io.read_bits(config, 32);
+            io.read_bits(config, 1 + turn_around_cycles);
+            Err(DapError::SwdError(ack))
+        }
+    }
+}
+
+/// Clocks out `count` bits of `data` (LSB of the first byte first) with `SwdBitIo`.
+pub fn write_sequence_bits<T: SwdBitIo>(io: &mut T, config: &SwdIoConfig, count: usize, data: &[u8]) {
+    let mut offset = 0;
+    while offset < count {
+        let bits = (count - offset).min(32);
+        let word = data[offset / 8..(offset + bits).div_ceil(8)]
+            .iter()
+            .enumerate()
+            .fold(0u32, |word, (index, byte)| word | (*byte as u32) << (index * 8));
+        io.write_bits(config, bits, word);
+        offset += bits;
+    }
+}
+
+/// Clocks in `count` bits into `data` (LSB of the first byte first) with `SwdBitIo`.
+pub fn read_sequence_bits<T: SwdBitIo>(io: &mut T, config: &SwdIoConfig, count: usize, data: &mut [u8]) {
+    let mut offset = 0;
+    while offset < count {
+        let bits = (count - offset).min(32);
+        let word = io.read_bits(config, bits);
+        for (index, byte) in data[offset / 8..(offset + bits).div_ceil(8)].iter_mut().enumerate() {
+            *byte = (word >> (index * 8)) as u8;
+        }
+        offset += bits;
+    }
+}
@@ -0,0 +1,319 @@
+// Copyright 2023 Kenta Ida
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use heapless::Deque;
+
+/// Size of the buffer which holds the captured SWO trace data.
+pub const SWO_BUFFER_SIZE: usize = 4096;
+
+// DAP_SWO_Transport transports
+pub const SWO_TRANSPORT_NONE: u8 = 0;
+pub const SWO_TRANSPORT_DATA: u8 = 1;
+pub const SWO_TRANSPORT_STREAM: u8 = 2;
+
+// DAP_SWO_Status trace status bits
+pub const SWO_STATUS_CAPTURE: u8 = 1 << 0;
+pub const SWO_STATUS_STREAM_ERROR: u8 = 1 << 6;
+pub const SWO_STATUS_BUFFER_OVERRUN: u8 = 1 << 7;
+
+/// Capture mode selected by DAP_SWO_Mode.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SwoMode {
+    Off,
+    Uart,
+    Manchester,
+}
+
+impl SwoMode {
+    pub fn from_u8(mode: u8) -> Option<Self> {
+        match mode {
+            0 => Some(Self::Off),
+            1 => Some(Self::Uart),
+            2 => Some(Self::Manchester),
+            _ => None,
+        }
+    }
+}
+
+/// Receiver of the SWO pin.
+pub trait SwoReceiver {
+    /// Selects the capture mode. Returns false if the mode is not supported.
+    fn set_mode(&mut self, mode: SwoMode) -> bool;
+    /// Sets the baudrate and returns the actual baudrate, or 0 if it cannot be set.
+    fn set_baudrate(&mut self, baudrate: u32) -> u32;
+    fn start(&mut self);
+    fn stop(&mut self);
+    /// Returns a received byte. `nb::Error::Other` means the data was lost by a framing error or an overrun.
+    fn read(&mut self) -> nb::Result<u8, ()>;
+}
+
+/// `SwoReceiver` of the probe without the SWO pin.
+pub struct NoSwo;
+
+impl SwoReceiver for NoSwo {
+    fn set_mode(&mut self, mode: SwoMode) -> bool {
+        mode == SwoMode::Off
+    }
+
+    fn set_baudrate(&mut self, _baudrate: u32) -> u32 {
+        0
+    }
+
+    fn start(&mut self) {}
+
+    fn stop(&mut self) {}
+
+    fn read(&mut self) -> nb::Result<u8, ()> {
+        Err(nb::Error::WouldBlock)
+    }
+}
+
+/// `SwoReceiver` which switches between a UART mode receiver and a Manchester mode receiver by the mode.
+///
+/// Each receiver is given `SwoMode::Off` while the other one is selected.
+pub struct DualModeSwo<U: SwoReceiver, M: SwoReceiver> {
+    uart: U,
+    manchester: M,
+    mode: SwoMode,
+}
+
+impl<U: SwoReceiver, M: SwoReceiver> DualModeSwo<U, M> {
+    pub fn new(uart: U, manchester: M) -> Self {
+        Self {
+            uart,
+            manchester,
+            mode: SwoMode::Off,
+        }
+    }
+
+    fn selected(&mut self) -> Option<&mut dyn SwoReceiver> {
+        match self.mode {
+            SwoMode::Off => None,
+            SwoMode::Uart => Some(&mut self.uart),
+            SwoMode::Manchester => Some(&mut self.manchester),
+        }
+    }
+}
+
+impl<U: SwoReceiver, M: SwoReceiver> SwoReceiver for DualModeSwo<U, M> {
+    fn set_mode(&mut self, mode: SwoMode) -> bool {
+        let (uart_mode, manchester_mode) = match mode {
+            SwoMode::Off => (SwoMode::Off, SwoMode::Off),
+            SwoMode::Uart => (SwoMode::Uart, SwoMode::Off),
+            SwoMode::Manchester => (SwoMode::Off, SwoMode::Manchester),
+        };
+        let supported = self.uart.set_mode(uart_mode) & self.manchester.set_mode(manchester_mode);
+        self.mode = if supported { mode } else { SwoMode::Off };
+        supported
+    }
+
+    fn set_baudrate(&mut self, baudrate: u32) -> u32 {
+        self.selected().map_or(0, |receiver| receiver.set_baudrate(baudrate))
+    }
+
+    fn start(&mut self) {
+        if let Some(receiver) = self.selected() {
+            receiver.start();
+        }
+    }
+
+    fn stop(&mut self) {
+        if let Some(receiver) = self.selected() {
+            receiver.stop();
+        }
+    }
+
+    fn read(&mut self) -> nb::Result<u8, ()> {
+        self.selected().map_or(Err(nb::Error::WouldBlock), |receiver| receiver.read())
+    }
+}
+
+/// SWO trace capture state with the buffer of the captured data.
+pub struct SwoTrace<R: SwoReceiver> {
+    receiver: R,
+    buffer: Deque<u8, SWO_BUFFER_SIZE>,
+    // 捕捉を開始してから受信したバイト数
+    index: u32,
+    transport: u8,
+    mode: SwoMode,
+    capture: bool,
+    error: bool,
+    overrun: bool,
+    streaming: bool,
+}
+
+impl<R: SwoReceiver> SwoTrace<R> {
+    /// `streaming` tells whether the transport has the endpoint to stream the trace data.
+    pub fn new(receiver: R, streaming: bool) -> Self {
+        Self {
+            receiver,
+            buffer: Deque::new(),
+            index: 0,
+            transport: SWO_TRANSPORT_NONE,
+            mode: SwoMode::Off,
+            capture: false,
+            error: false,
+            overrun: false,
+            streaming,
+        }
+    }
+
+    pub fn receiver(&mut self) -> &mut R {
+        &mut self.receiver
+    }
+
+    pub fn is_streaming(&self) -> bool {
+        self.streaming
+    }
+
+    pub fn transport(&self) -> u8 {
+        self.transport
+    }
+
+    pub fn is_capturing(&self) -> bool {
+        self.capture
+    }
+
+    /// Number of bytes in the buffer.
+    pub fn len(&self) -> usize {
+        self.buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buffer.is_empty()
+    }
+
+    /// Number of bytes captured since the capture started.
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+
+    pub fn set_transport(&mut self, transport: u8) -> bool {
+        let supported = match transport {
+            SWO_TRANSPORT_NONE | SWO_TRANSPORT_DATA => true,
+            SWO_TRANSPORT_STREAM => self.streaming,
+            _ => false,
+        };
+        // 捕捉中は変更できない
+        if !supported || self.capture {
+            return false;
+        }
+        self.transport = transport;
+        true
+    }
+
+    pub fn set_mode(&mut self, mode: u8) -> bool {
+        if self.capture {
+            return false;
+        }
+        match SwoMode::from_u8(mode) {
+            Some(mode) if self.receiver.set_mode(mode) => {
+                self.mode = mode;
+                true
+            }
+            _ => {
+                // 対応していないモードが指定されたら捕捉できない状態にしておく
+                self.receiver.set_mode(SwoMode::Off);
+                self.mode = SwoMode::Off;
+                false
+            }
+        }
+    }
+
+    /// Sets the baudrate and returns the actual baudrate, or 0 if it cannot be set.
+    pub fn set_baudrate(&mut self, baudrate: u32) -> u32 {
+        if self.capture || self.mode == SwoMode::Off {
+            return 0;
+        }
+        let baudrate = self.receiver.set_baudrate(baudrate);
+        if baudrate == 0 {
+            // 受信できないボーレートなら、set_modeと同じく捕捉できない状態にしておく
+            self.receiver.set_mode(SwoMode::Off);
+            self.mode = SwoMode::Off;
+        }
+        baudrate
+    }
+
+    /// Starts or stops the capture. Returns false if the capture cannot be started.
+    pub fn control(&mut self, start: bool) -> bool {
+        if start == self.capture {
+            return true;
+        }
+        if start {
+            if self.mode == SwoMode::Off {
+                return false;
+            }
+            // 前回の捕捉で残っているデータは捨てる
+            self.buffer.clear();
+            self.index = 0;
+            self.error = false;
+            self.overrun = false;
+            self.receiver.start();
+        } else {
+            self.poll();
+            self.receiver.stop();
+        }
+        self.capture = start;
+        true
+    }
+
+    /// Returns the trace status (`SWO_STATUS_*`) and clears the error bits.
+    pub fn take_status(&mut self) -> u8 {
+        let mut status = 0;
+        if self.capture {
+            status |= SWO_STATUS_CAPTURE;
+        }
+        if self.error {
+            status |= SWO_STATUS_STREAM_ERROR;
+        }
+        if self.overrun {
+            status |= SWO_STATUS_BUFFER_OVERRUN;
+        }
+        self.error = false;
+        self.overrun = false;
+        status
+    }
+
+    /// Moves the data received by the receiver to the buffer.
+    pub fn poll(&mut self) {
+        if !self.capture {
+            return;
+        }
+        loop {
+            match self.receiver.read() {
+                Ok(data) => {
+                    if self.buffer.push_back(data).is_ok() {
+                        self.index = self.index.wrapping_add(1);
+                    } else {
+                        // バッファが一杯なので新しいデータを捨てる
+                        self.overrun = true;
+                    }
+                }
+                Err(nb::Error::WouldBlock) => break,
+                Err(nb::Error::Other(())) => self.error = true,
+            }
+        }
+    }
+
+    /// Moves the oldest data in the buffer to `data` and returns the number of bytes moved.
+    pub fn read(&mut self, data: &mut [u8]) -> usize {
+        let length = data.len().min(self.buffer.len());
+        for byte in data[..length].iter_mut() {
+            *byte = self.buffer.pop_front().unwrap_or_default();
+        }
+        length
+    }
+}
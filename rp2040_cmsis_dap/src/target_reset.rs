@@ -0,0 +1,104 @@
+// Copyright 2023 Kenta Ida
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use crate::dap_processor::DapError;
+use crate::swdio::*;
+
+/// Target specific reset sequence run by DAP_ResetTarget.
+pub type ResetTargetFn<S> = fn(&mut S, &SwdIoConfig) -> core::result::Result<(), DapError>;
+
+// nRESETのパルス幅と、解除後にHighに戻るまでの最大待ち時間
+const NRESET_PULSE_US: u32 = 10_000;
+const NRESET_RELEASE_TIMEOUT_US: u32 = 100_000;
+
+// 32bitアクセス、アドレスのインクリメントなし
+const CSW_VALUE: u32 = 0x2300_0000 | CSW_SIZE_32;
+
+/// Resets the target by pulsing nRESET low.
+///
+/// Fails if `swdio` has no nRESET pin, since nRESET is then reported low and never seen released.
+pub fn reset_by_nreset<S: SwdIo>(swdio: &mut S, _config: &SwdIoConfig) -> core::result::Result<(), DapError> {
+    swdio.set_swj_pins(0, SWJ_PIN_NRESET);
+    swdio.delay_us(NRESET_PULSE_US);
+    swdio.set_swj_pins(SWJ_PIN_NRESET, SWJ_PIN_NRESET);
+    // 外部のコンデンサなどでHighに戻るのが遅れることがあるので待つ
+    let mut elapsed_us = 0;
+    while swdio.get_swj_pins() & SWJ_PIN_NRESET == 0 {
+        if elapsed_us >= NRESET_RELEASE_TIMEOUT_US {
+            return Err(DapError::ExceedRetryCount);
+        }
+        swdio.delay_us(1);
+        elapsed_us += 1;
+    }
+    Ok(())
+}
+
+/// Resets a Cortex-M target by setting AIRCR.SYSRESETREQ through the MEM-AP at APSEL 0.
+///
+/// Works without the nRESET pin, but the SWD port must be connected and the debug domain powered up.
+pub fn reset_by_sysresetreq<S: SwdIo>(swdio: &mut S, config: &SwdIoConfig) -> core::result::Result<(), DapError> {
+    let mut transfer = |request: SwdRequest, data: u32| {
+        retry_on_wait(config.wait_retry, || swdio.swd_transfer(config, request, data))
+    };
+    // APSEL = 0, APBANKSEL = 0
+    transfer(DP_SELECT, 0)?;
+    transfer(DAP_TRANSFER_APNDP | AP_CSW, CSW_VALUE)?;
+    transfer(DAP_TRANSFER_APNDP | AP_TAR, AIRCR)?;
+    transfer(DAP_TRANSFER_APNDP | AP_DRW, AIRCR_VECTKEY | AIRCR_SYSRESETREQ)?;
+    // 書き込みが完了したことを確認する。リセット中に応答がなくなるターゲットもあるので結果は見ない
+    transfer(DAP_TRANSFER_RNW | DP_RDBUFF, 0).ok();
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim_target::SimulatedTarget;
+
+    #[test]
+    fn sysresetreq_retries_wait() {
+        let mut target = SimulatedTarget::<256>::new(0x2000_0000);
+        target.connect();
+        target.inject_wait(2);
+        assert_eq!(reset_by_sysresetreq(&mut target, &SwdIoConfig::default()), Ok(()));
+        assert_eq!(target.reset_count(), 1);
+    }
+
+    #[test]
+    fn sysresetreq_fails_when_wait_retry_runs_out() {
+        let mut target = SimulatedTarget::<256>::new(0x2000_0000);
+        target.connect();
+        target.inject_wait(2);
+        let config = SwdIoConfig {
+            wait_retry: 1,
+            ..Default::default()
+        };
+        assert_eq!(
+            reset_by_sysresetreq(&mut target, &config),
+            Err(DapError::SwdError(DAP_TRANSFER_WAIT))
+        );
+        assert_eq!(target.reset_count(), 0);
+    }
+
+    #[test]
+    fn nreset_pulse() {
+        let mut target = SimulatedTarget::<256>::new(0x2000_0000);
+        assert_eq!(reset_by_nreset(&mut target, &SwdIoConfig::default()), Ok(()));
+        assert_eq!(target.reset_count(), 1);
+        assert!(!target.is_reset_asserted());
+        assert_eq!(target.elapsed_us(), NRESET_PULSE_US as u64);
+    }
+}
@@ -0,0 +1,86 @@
+// Copyright 2023 Kenta Ida
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use embedded_hal::serial::{Read, Write};
+use fugit::HertzU32;
+use rp_pico::hal::uart::{
+    DataBits, Disabled, Parity, ReadErrorType, StopBits, UartConfig, UartDevice, UartPeripheral, ValidUartPinout,
+};
+
+use crate::dap_uart::{
+    UartError, UartIo, UartLineConfig, UartParity, UartStopBits, UART_CONFIG_ERROR_STOP_BITS,
+};
+use crate::rp2040_uart::Rp2040Uart;
+
+/// `UartIo` which communicates with the target by a UART peripheral.
+///
+/// The UART is disabled until it is configured by DAP_UART_Configure or the line coding of the COM port.
+pub struct TargetUart<D: UartDevice, P: ValidUartPinout<D>> {
+    uart: Rp2040Uart<D, P>,
+}
+
+impl<D: UartDevice, P: ValidUartPinout<D>> TargetUart<D, P> {
+    pub fn new(uart: UartPeripheral<Disabled, D, P>, peripheral_clock_hz: u32) -> Self {
+        Self {
+            uart: Rp2040Uart::new(uart, peripheral_clock_hz),
+        }
+    }
+}
+
+impl<D: UartDevice, P: ValidUartPinout<D>> UartIo for TargetUart<D, P> {
+    fn configure(&mut self, config: &UartLineConfig) -> Result<u32, u8> {
+        let data_bits = match config.data_bits {
+            5 => DataBits::Five,
+            6 => DataBits::Six,
+            7 => DataBits::Seven,
+            _ => DataBits::Eight,
+        };
+        let parity = match config.parity {
+            UartParity::None => None,
+            UartParity::Odd => Some(Parity::Odd),
+            UartParity::Even => Some(Parity::Even),
+        };
+        let stop_bits = match config.stop_bits {
+            UartStopBits::One => StopBits::One,
+            UartStopBits::Two => StopBits::Two,
+            // PL011は1.5ストップビットに対応しない
+            UartStopBits::OnePointFive => return Err(UART_CONFIG_ERROR_STOP_BITS),
+        };
+        let config = UartConfig::new(HertzU32::Hz(config.baudrate), data_bits, parity, stop_bits);
+        // 範囲外のボーレートでは前の設定のままにして、実際のボーレートとして0を返す
+        Ok(self.uart.enable(config))
+    }
+
+    fn read(&mut self) -> nb::Result<u8, UartError> {
+        match self.uart.enabled() {
+            Some(uart) => uart.read().map_err(|err| {
+                err.map(|err| match err {
+                    ReadErrorType::Overrun => UartError::Overrun,
+                    ReadErrorType::Parity => UartError::Parity,
+                    ReadErrorType::Break | ReadErrorType::Framing => UartError::Framing,
+                })
+            }),
+            None => Err(nb::Error::WouldBlock),
+        }
+    }
+
+    fn write(&mut self, data: u8) -> nb::Result<(), ()> {
+        match self.uart.enabled() {
+            Some(uart) => uart.write(data).map_err(|err| err.map(|_| ())),
+            None => Err(nb::Error::WouldBlock),
+        }
+    }
+}
@@ -0,0 +1,75 @@
+// Copyright 2023 Kenta Ida
+//
+// SPDX-License-Identifier: Apache-2.0
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+use embedded_hal::serial::Read;
+use fugit::HertzU32;
+use rp_pico::hal::uart::{DataBits, Disabled, StopBits, UartConfig, UartDevice, UartPeripheral, ValidUartPinout};
+
+use rp2040_cmsis_dap::rp2040_uart::Rp2040Uart;
+use rp2040_cmsis_dap::swo::{SwoMode, SwoReceiver};
+
+/// `SwoReceiver` which captures SWO in UART mode by a UART peripheral.
+///
+/// Only the RX pin of the UART is used.
+pub struct UartSwo<D: UartDevice, P: ValidUartPinout<D>> {
+    uart: Rp2040Uart<D, P>,
+    baudrate: u32,
+}
+
+impl<D: UartDevice, P: ValidUartPinout<D>> UartSwo<D, P> {
+    pub fn new(uart: UartPeripheral<Disabled, D, P>, peripheral_clock_hz: u32) -> Self {
+        Self {
+            uart: Rp2040Uart::new(uart, peripheral_clock_hz),
+            baudrate: 0,
+        }
+    }
+}
+
+impl<D: UartDevice, P: ValidUartPinout<D>> SwoReceiver for UartSwo<D, P> {
+    fn set_mode(&mut self, mode: SwoMode) -> bool {
+        // Manchester符号化には対応しない
+        mode != SwoMode::Manchester
+    }
+
+    fn set_baudrate(&mut self, baudrate: u32) -> u32 {
+        self.baudrate = self.uart.actual_baudrate(baudrate);
+        self.baudrate
+    }
+
+    fn start(&mut self) {
+        // ボーレートが設定されていなければ受信できない
+        if self.baudrate == 0 {
+            return;
+        }
+        if self.uart.enabled().is_some() {
+            return;
+        }
+        // SWOはスタートビット、8ビットデータ、ストップビットのNRZ
+        let config = UartConfig::new(HertzU32::Hz(self.baudrate), DataBits::Eight, None, StopBits::One);
+        self.uart.enable(config);
+    }
+
+    fn stop(&mut self) {
+        self.uart.disable();
+    }
+
+    fn read(&mut self) -> nb::Result<u8, ()> {
+        match self.uart.enabled() {
+            Some(uart) => uart.read().map_err(|err| err.map(|_| ())),
+            None => Err(nb::Error::WouldBlock),
+        }
+    }
+}